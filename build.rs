@@ -0,0 +1,43 @@
+//! Records details about the build, which are printed by the `version` subcommand.
+
+use std::env;
+use std::process::Command;
+
+fn main() {
+    let commit = command_output("git", &["rev-parse", "--short", "HEAD"]);
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
+    let rust_version = command_output(&rustc, &["--version"]);
+
+    // Cargo sets a CARGO_FEATURE_<NAME> variable for every enabled feature.
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(name, _)| {
+            name.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+
+    println!(
+        "cargo:rustc-env=BUILD_GIT_COMMIT={}",
+        commit.unwrap_or_else(|| String::from("unknown"))
+    );
+    println!(
+        "cargo:rustc-env=BUILD_RUST_VERSION={}",
+        rust_version.unwrap_or_else(|| String::from("unknown"))
+    );
+    println!("cargo:rustc-env=BUILD_FEATURES={}", features.join(","));
+
+    // Builds from a source archive have no .git directory, which is fine.
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let output = String::from_utf8(output.stdout).ok()?;
+    Some(output.trim().to_string())
+}
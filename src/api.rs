@@ -0,0 +1,929 @@
+//! Talking to the Cloudflare API.
+
+use crate::color::paint_warning;
+use crate::options::{CloudflareOptions, Options, OutputFormat};
+use cloudflare::endpoints::dns::{self, DnsContent, DnsRecord};
+use cloudflare::endpoints::{user, zone};
+use cloudflare::framework::auth::AuthClient;
+use cloudflare::framework::response::{
+    map_api_response, ApiErrors, ApiFailure, ApiResponse, ApiResult, ApiSuccess,
+};
+use cloudflare::framework::HttpApiClient as CloudflareClient;
+use reqwest::blocking::Client;
+use reqwest::{StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use std::net::Ipv4Addr;
+use std::thread;
+use std::time::Duration;
+
+/// How many DNS records to ask for per page when listing all records in a zone.
+const RECORDS_PER_PAGE: &str = "100";
+/// How many zones to ask for per page when deriving the zone from the record name. This is the
+/// most that the API allows.
+const ZONES_PER_PAGE: &str = "50";
+/// How many times to try looking up the zone before giving up.
+const ZONE_LOOKUP_ATTEMPTS: u32 = 3;
+/// How long to wait before trying the zone lookup again.
+const ZONE_LOOKUP_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// How many records to list when a record cannot be found.
+const MAX_DESCRIBED_RECORDS: usize = 10;
+
+/// The permissions that a token has, as returned when looking up the token itself.
+#[derive(Deserialize, Debug)]
+struct TokenDetails {
+    #[serde(default)]
+    policies: Vec<TokenPolicy>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenPolicy {
+    effect: String,
+    #[serde(default)]
+    permission_groups: Vec<PermissionGroup>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PermissionGroup {
+    name: String,
+}
+
+impl ApiResult for TokenDetails {}
+
+/// What is known about a token. The permissions are only known when the token is allowed to read
+/// its own details, which most tokens are not.
+#[derive(Serialize, Debug)]
+struct TokenInfo {
+    id: String,
+    status: String,
+    permissions: Option<Vec<String>>,
+}
+
+fn token_info(
+    options: &CloudflareOptions,
+    cloudflare: &CloudflareClient,
+) -> Result<TokenInfo, String> {
+    let status = cloudflare
+        .request(&user::GetUserTokenStatus {})
+        .map_err(|err| {
+            format!(
+                "The API token could not be verified: {}",
+                format_cloudflare_api_failure(err)
+            )
+        })?
+        .result;
+
+    let details: Option<TokenDetails> =
+        cloudflare_get(options, &format!("user/tokens/{}", status.id), &[])
+            .ok()
+            .map(|response| response.result);
+
+    let permissions = details.map(|details| {
+        details
+            .policies
+            .into_iter()
+            .filter(|policy| policy.effect == "allow")
+            .flat_map(|policy| policy.permission_groups.into_iter())
+            .map(|group| group.name)
+            .collect()
+    });
+
+    Ok(TokenInfo {
+        id: status.id,
+        status: status.status,
+        permissions,
+    })
+}
+
+/// Checks that the token is active and, when the token is allowed to read its own details, that
+/// it has the permissions needed to update the record. Tokens usually cannot read their own
+/// details, in which case only the status is checked.
+///
+/// A dry run never writes, so a missing write permission is only reported as a warning. That way
+/// the dry run still tries everything else that a real run does.
+pub(crate) fn preflight(
+    options: &CloudflareOptions,
+    verbose: bool,
+    dry_run: bool,
+    cloudflare: &CloudflareClient,
+) -> Result<(), String> {
+    let info = token_info(options, cloudflare)?;
+
+    if info.status != "active" {
+        return Err(format!(
+            "The API token is {}. Create a new token or reactivate it.",
+            info.status
+        ));
+    }
+
+    let permissions = match &info.permissions {
+        Some(permissions) => permissions,
+        None => {
+            if dry_run {
+                eprintln!(
+                    "Token is active, but it may not read its own permissions. The dry run cannot \
+                     tell if a real run would be allowed to write the record."
+                );
+            } else if verbose {
+                eprintln!(
+                    "Token is active, but it may not read its own permissions. Skipping the \
+                     permission check."
+                );
+            }
+            return Ok(());
+        }
+    };
+    let has_permission = |name: &str| permissions.iter().any(|permission| permission == name);
+
+    let mut missing = Vec::new();
+    if !has_permission("DNS Write") {
+        if dry_run {
+            eprintln!(
+                "{} The API token is missing the Zone:DNS:Edit permission. The dry run continues, \
+                 but a real run would fail to write the record.",
+                paint_warning("Warning:")
+            );
+        } else {
+            missing.push("Zone:DNS:Edit");
+        }
+    }
+    if options.zone_options.id.is_none() && !has_permission("Zone Read") {
+        missing.push("Zone:Zone:Read (or pass --zone-id)");
+    }
+
+    if missing.is_empty() {
+        if verbose {
+            eprintln!("Token is active and has the needed permissions");
+        }
+        Ok(())
+    } else {
+        Err(format!(
+            "The API token is missing these permissions: {}. Edit the token in the Cloudflare \
+             dashboard, or pass --no-preflight to skip this check.",
+            missing.join(", ")
+        ))
+    }
+}
+
+/// Prints the status, ID and permissions of the token on stdout. Fails if the token is not
+/// active.
+pub fn verify_token(
+    options: &Options,
+    cloudflare_options: &CloudflareOptions,
+) -> Result<(), String> {
+    let cloudflare = cloudflare_options.client()?;
+    let info = token_info(cloudflare_options, &cloudflare)?;
+
+    match options.output {
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&info)
+                .map_err(|err| format!("Failed to serialize token: {}", err))?
+        ),
+        OutputFormat::Text => {
+            println!("Status:      {}", info.status);
+            println!("ID:          {}", info.id);
+            match &info.permissions {
+                Some(permissions) => println!("Permissions: {}", permissions.join(", ")),
+                None => println!("Permissions: unknown, the token may not read its own details"),
+            }
+        }
+    }
+
+    if info.status == "active" {
+        Ok(())
+    } else {
+        Err(format!("The API token is {}.", info.status))
+    }
+}
+
+/// Finds the ID of the zone. Without a zone ID or name, the zone is derived from the record
+/// name when there is one.
+pub(crate) fn find_zone_id(
+    options: &CloudflareOptions,
+    record_name: Option<&str>,
+    verbose: bool,
+    cloudflare: &CloudflareClient,
+) -> Result<String, String> {
+    if let Some(id) = &options.zone_options.id {
+        return Ok(id.to_owned());
+    }
+
+    let name = match (&options.zone_options.name, record_name) {
+        (Some(name), _) => name,
+        (None, Some(record_name)) => return derive_zone_id(options, record_name, verbose),
+        (None, None) => {
+            return Err(String::from(
+                "Neither Zone ID or Zone Name was specified. Pass --zone-id or --zone-name.",
+            ))
+        }
+    };
+
+    if verbose {
+        eprint!("Resolving Zone ID… ");
+    }
+
+    let response = retry_zone_lookup(verbose, || match &options.account_id {
+        Some(account_id) => list_zones_in_account(options, name, account_id),
+        None => cloudflare.request(&zone::ListZones {
+            params: zone::ListZonesParams {
+                name: Some(name.to_owned()),
+                ..Default::default()
+            },
+        }),
+    });
+
+    let zones = response
+        .map_err(|err| {
+            dbg!(&err);
+            format!(
+                "Failed to retreive zone ID: {}",
+                format_cloudflare_api_failure(err)
+            )
+        })?
+        .result;
+
+    if zones.is_empty() {
+        return Err(format!(
+            "Failed to retrieve zone ID: The token has access to no zone named {}. Check the zone \
+             name, and that the zone is included in the zone resources of the token.",
+            name
+        ));
+    }
+
+    let found_names = zones
+        .iter()
+        .map(|zone| zone.name.as_str())
+        .collect::<Vec<&str>>()
+        .join(", ");
+    let zone = zones
+        .iter()
+        .find(|zone| &zone.name == name)
+        .ok_or_else(|| {
+            format!(
+                "Failed to retrieve zone ID: No zone named {} found. Found: {}",
+                name, found_names
+            )
+        })?;
+
+    if verbose {
+        eprintln!("OK. Found {}", zone.id);
+    }
+
+    Ok(zone.id.clone())
+}
+
+/// Finds the zone of a record by listing every zone that the token can access and picking the
+/// longest one that the record name ends with. Asking for the zones instead of guessing from the
+/// labels makes this work for names under multi-label suffixes like "co.uk".
+fn derive_zone_id(
+    options: &CloudflareOptions,
+    record_name: &str,
+    verbose: bool,
+) -> Result<String, String> {
+    let record_name = record_name.trim_start_matches('.');
+    if verbose {
+        eprint!("Deriving zone from {}… ", record_name);
+    }
+
+    let zones = list_all_zones(options, verbose)?;
+    let matching: Vec<&zone::Zone> = zones
+        .iter()
+        .filter(|zone| {
+            record_name == zone.name || record_name.ends_with(&format!(".{}", zone.name))
+        })
+        .collect();
+    let longest = matching
+        .iter()
+        .map(|zone| zone.name.len())
+        .max()
+        .ok_or_else(|| {
+            let found_names = zones
+                .iter()
+                .map(|zone| zone.name.as_str())
+                .collect::<Vec<&str>>()
+                .join(", ");
+            format!(
+                "Failed to derive the zone of {}: The token has access to no zone that contains \
+                 it. Found: {}. Pass --zone-name or --zone-id.",
+                record_name,
+                if found_names.is_empty() {
+                    "none"
+                } else {
+                    &found_names
+                }
+            )
+        })?;
+    let candidates: Vec<&&zone::Zone> = matching
+        .iter()
+        .filter(|zone| zone.name.len() == longest)
+        .collect();
+
+    match candidates.as_slice() {
+        [zone] => {
+            if verbose {
+                eprintln!("OK. Found {} ({})", zone.name, zone.id);
+            }
+            Ok(zone.id.clone())
+        }
+        _ => Err(format!(
+            "Failed to derive the zone of {}: The token has access to {} zones named {}. Pass \
+             --account-id, --zone-id or --zone-name to pick one.",
+            record_name,
+            candidates.len(),
+            candidates[0].name
+        )),
+    }
+}
+
+/// Lists every zone that the token can access, inside the account if one is given.
+fn list_all_zones(options: &CloudflareOptions, verbose: bool) -> Result<Vec<zone::Zone>, String> {
+    let mut zones = Vec::new();
+    let mut page: u64 = 1;
+
+    loop {
+        let page_string = page.to_string();
+        let mut query = vec![("page", page_string.as_str()), ("per_page", ZONES_PER_PAGE)];
+        if let Some(account_id) = &options.account_id {
+            query.push(("account.id", account_id.as_str()));
+        }
+
+        let response: ApiSuccess<Vec<zone::Zone>> =
+            retry_zone_lookup(verbose, || cloudflare_get(options, "zones", &query)).map_err(
+                |err| {
+                    format!(
+                        "Failed to list zones: {}",
+                        format_cloudflare_api_failure(err)
+                    )
+                },
+            )?;
+
+        let total_pages = response
+            .result_info
+            .as_ref()
+            .and_then(|info| info.get("total_pages"))
+            .and_then(|total_pages| total_pages.as_u64())
+            .unwrap_or(1);
+
+        zones.extend(response.result);
+
+        if page >= total_pages {
+            return Ok(zones);
+        }
+        page += 1;
+    }
+}
+
+/// Sends the zone lookup again after a short delay when it fails in a way that is likely to be
+/// temporary, like a network error or a server error. Nothing else can happen before the zone is
+/// known, so a brief outage should not abort the run this early.
+fn retry_zone_lookup<T: ApiResult>(
+    verbose: bool,
+    request: impl Fn() -> ApiResponse<T>,
+) -> ApiResponse<T> {
+    let mut attempt = 1;
+    loop {
+        match request() {
+            Err(failure) if attempt < ZONE_LOOKUP_ATTEMPTS && is_transient(&failure) => {
+                if verbose {
+                    eprintln!(
+                        "Zone lookup failed ({} of {} attempts), trying again…",
+                        attempt, ZONE_LOOKUP_ATTEMPTS
+                    );
+                }
+                thread::sleep(ZONE_LOOKUP_RETRY_DELAY);
+                attempt += 1;
+            }
+            response => return response,
+        }
+    }
+}
+
+/// Whether the request might work if it is sent again. Errors in the request itself, like a
+/// token without access, will not go away on their own.
+fn is_transient(failure: &ApiFailure) -> bool {
+    match failure {
+        ApiFailure::Error(status, _) => {
+            status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+        }
+        ApiFailure::Invalid(_) => true,
+    }
+}
+
+/// `ListZonesParams` has no way to filter on the account, so this request is sent without going
+/// through the `cloudflare` crate's endpoint types.
+fn list_zones_in_account(
+    options: &CloudflareOptions,
+    name: &str,
+    account_id: &str,
+) -> ApiResponse<Vec<zone::Zone>> {
+    cloudflare_get(
+        options,
+        "zones",
+        &[("name", name), ("account.id", account_id)],
+    )
+}
+
+/// Sends a GET request to a Cloudflare API endpoint, parsing the response the same way as the
+/// `cloudflare` crate does. Use this for endpoints or parameters that the crate does not support.
+fn cloudflare_get<T: ApiResult>(
+    options: &CloudflareOptions,
+    path: &str,
+    query: &[(&str, &str)],
+) -> ApiResponse<T> {
+    let mut url = Url::from(&options.environment())
+        .join(path)
+        .expect("Programmer error: Invalid API path");
+    url.query_pairs_mut().extend_pairs(query);
+
+    let response = options
+        .resolve_hosts(Client::builder())
+        .build()?
+        .get(url)
+        .auth(&options.credentials())
+        .send()?;
+
+    map_api_response(response)
+}
+
+/// A custom hostname from Cloudflare for SaaS. Only the fields needed to find its origin are
+/// parsed.
+#[derive(Deserialize, Debug)]
+struct CustomHostname {
+    hostname: String,
+    custom_origin_server: Option<String>,
+}
+
+impl ApiResult for CustomHostname {}
+
+#[derive(Deserialize, Debug)]
+struct FallbackOrigin {
+    origin: Option<String>,
+}
+
+impl ApiResult for FallbackOrigin {}
+
+/// Finds the origin that Cloudflare for SaaS sends the traffic for a custom hostname to. That is
+/// the custom origin server of the hostname if it has one, and the fallback origin of the zone
+/// otherwise. Either way the origin is the name of a record in the zone, which is what needs to
+/// point to the IP.
+pub fn saas_origin(
+    options: &Options,
+    cloudflare_options: &CloudflareOptions,
+    custom_hostname_id: &str,
+) -> Result<String, String> {
+    let cloudflare = cloudflare_options.client()?;
+    let zone_id = find_zone_id(cloudflare_options, None, options.is_verbose(), &cloudflare)?;
+
+    let custom_hostname: CustomHostname = cloudflare_get(
+        cloudflare_options,
+        &format!("zones/{}/custom_hostnames/{}", zone_id, custom_hostname_id),
+        &[],
+    )
+    .map_err(|err| {
+        format!(
+            "Failed to look up custom hostname {}: {}",
+            custom_hostname_id,
+            format_cloudflare_api_failure(err)
+        )
+    })?
+    .result;
+
+    if let Some(origin) = custom_hostname.custom_origin_server {
+        if options.is_verbose() {
+            eprintln!(
+                "{} uses the custom origin server {}",
+                custom_hostname.hostname, origin
+            );
+        }
+        return Ok(origin);
+    }
+
+    let fallback_origin: FallbackOrigin = cloudflare_get(
+        cloudflare_options,
+        &format!("zones/{}/custom_hostnames/fallback_origin", zone_id),
+        &[],
+    )
+    .map_err(|err| {
+        format!(
+            "Failed to look up the fallback origin: {}",
+            format_cloudflare_api_failure(err)
+        )
+    })?
+    .result;
+
+    match fallback_origin.origin {
+        Some(origin) => {
+            if options.is_verbose() {
+                eprintln!(
+                    "{} uses the fallback origin {}",
+                    custom_hostname.hostname, origin
+                );
+            }
+            Ok(origin)
+        }
+        None => Err(format!(
+            "{} has no custom origin server, and the zone has no fallback origin.",
+            custom_hostname.hostname
+        )),
+    }
+}
+
+/// A DNS record of any type. The `DnsRecord` type from the `cloudflare` crate fails to parse
+/// record types it doesn't know about (CAA, PTR, …), so this is used when listing every record in a
+/// zone.
+#[derive(Deserialize, Serialize, Debug)]
+struct ListedRecord {
+    id: String,
+    name: String,
+    #[serde(rename = "type")]
+    record_type: String,
+    #[serde(default)]
+    content: String,
+    ttl: u32,
+    #[serde(default)]
+    proxied: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(transparent)]
+struct ListedRecords(Vec<ListedRecord>);
+
+impl ApiResult for ListedRecords {}
+
+impl IntoIterator for ListedRecords {
+    type Item = ListedRecord;
+    type IntoIter = std::vec::IntoIter<ListedRecord>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+/// Lists all records in the zone, following the pagination until every page has been read.
+fn list_all_records<R: ApiResult + IntoIterator>(
+    options: &CloudflareOptions,
+    zone_id: &str,
+    record_type: Option<&str>,
+) -> Result<Vec<R::Item>, String> {
+    let path = format!("zones/{}/dns_records", zone_id);
+    let mut records = Vec::new();
+    let mut page: u64 = 1;
+
+    loop {
+        let page_string = page.to_string();
+        let mut query = vec![
+            ("page", page_string.as_str()),
+            ("per_page", RECORDS_PER_PAGE),
+        ];
+        if let Some(record_type) = record_type {
+            query.push(("type", record_type));
+        }
+
+        let response: ApiSuccess<R> = cloudflare_get(options, &path, &query).map_err(|err| {
+            format!(
+                "Failed to list DNS records for zone {}: {}",
+                zone_id,
+                format_cloudflare_api_failure(err)
+            )
+        })?;
+
+        let total_pages = response
+            .result_info
+            .as_ref()
+            .and_then(|info| info.get("total_pages"))
+            .and_then(|total_pages| total_pages.as_u64())
+            .unwrap_or(1);
+
+        records.extend(response.result);
+
+        if page >= total_pages {
+            return Ok(records);
+        }
+        page += 1;
+    }
+}
+
+/// Prints the DNS records in the zone on stdout, optionally filtered by type and name.
+pub fn list_records(
+    options: &Options,
+    cloudflare_options: &CloudflareOptions,
+    record_type: Option<&str>,
+    name_contains: Option<&str>,
+) -> Result<(), String> {
+    let cloudflare = cloudflare_options.client()?;
+    let zone_id = find_zone_id(cloudflare_options, None, options.is_verbose(), &cloudflare)?;
+
+    let record_type = record_type.map(|record_type| record_type.to_uppercase());
+    let mut records =
+        list_all_records::<ListedRecords>(cloudflare_options, &zone_id, record_type.as_deref())?;
+    if let Some(text) = name_contains {
+        records.retain(|record| record.name.contains(text));
+    }
+
+    match options.output {
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&records)
+                .map_err(|err| format!("Failed to serialize records: {}", err))?
+        ),
+        OutputFormat::Text => print_records_table(&records),
+    }
+
+    Ok(())
+}
+
+fn print_records_table(records: &[ListedRecord]) {
+    let rows: Vec<[String; 6]> = records
+        .iter()
+        .map(|record| {
+            [
+                record.name.clone(),
+                record.record_type.clone(),
+                record.content.clone(),
+                format_ttl(record.ttl),
+                record.proxied.to_string(),
+                record.id.clone(),
+            ]
+        })
+        .collect();
+
+    let header = ["NAME", "TYPE", "CONTENT", "TTL", "PROXIED", "ID"].map(String::from);
+    let mut widths = header.clone().map(|column| column.len());
+    for row in rows.iter() {
+        for (width, column) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(column.chars().count());
+        }
+    }
+
+    for row in Some(&header).into_iter().chain(rows.iter()) {
+        let line = row
+            .iter()
+            .zip(widths.iter())
+            .map(|(column, width)| format!("{:<width$}", column, width = width))
+            .collect::<Vec<String>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+}
+
+/// Fetches the A record with the name. When there is none, a CNAME record with the name is
+/// returned instead, since it would conflict with creating the A record.
+///
+/// `ListDnsRecordsParams` can only filter on the type together with the content, so this request
+/// is sent without going through the `cloudflare` crate's endpoint types. Filtering on the type
+/// also avoids other records with the same name, some of which the `cloudflare` crate cannot
+/// parse.
+pub(crate) fn fetch_current_dns_record(
+    options: &CloudflareOptions,
+    zone_id: &str,
+    record_name: &str,
+) -> Result<Option<DnsRecord>, String> {
+    let path = format!("zones/{}/dns_records", zone_id);
+
+    for record_type in ["A", "CNAME"].iter() {
+        let records: Vec<DnsRecord> = cloudflare_get(
+            options,
+            &path,
+            &[("name", record_name), ("type", record_type)],
+        )
+        .map_err(|err| {
+            format!(
+                "Failed to list DNS records for zone {}: {}",
+                zone_id,
+                format_cloudflare_api_failure(err)
+            )
+        })?
+        .result;
+
+        if let Some(record) = records
+            .into_iter()
+            .find(|record| record.name == record_name)
+        {
+            return Ok(Some(record));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Fetches every A record in the zone that is named the suffix or is below it. The suffix only
+/// matches whole labels, so "home.example.com" does not match "myhome.example.com".
+pub(crate) fn fetch_dns_records_with_suffix(
+    options: &CloudflareOptions,
+    zone_id: &str,
+    suffix: &str,
+) -> Result<Vec<DnsRecord>, String> {
+    let suffix = suffix.trim_start_matches('.').to_lowercase();
+    let subdomain_suffix = format!(".{}", suffix);
+    let mut records = list_all_records::<Vec<DnsRecord>>(options, zone_id, Some("A"))?;
+    records.retain(|record| {
+        let name = record.name.to_lowercase();
+        name == suffix || name.ends_with(&subdomain_suffix)
+    });
+    Ok(records)
+}
+
+/// Describes what records the zone has, to help spot a typo in the record name. Since this is
+/// only used to improve an error message, a failure to list the records results in an empty
+/// description.
+pub(crate) fn describe_zone_records(options: &CloudflareOptions, zone_id: &str) -> String {
+    let records = match list_all_records::<ListedRecords>(options, zone_id, None) {
+        Ok(records) => records,
+        Err(_) => return String::new(),
+    };
+
+    if records.is_empty() {
+        return String::from("The zone has no DNS records at all.");
+    }
+
+    let mut names: Vec<String> = records
+        .iter()
+        .take(MAX_DESCRIBED_RECORDS)
+        .map(|record| format!("{} ({})", record.name, record.record_type))
+        .collect();
+    if records.len() > MAX_DESCRIBED_RECORDS {
+        names.push(format!(
+            "and {} more",
+            records.len() - MAX_DESCRIBED_RECORDS
+        ));
+    }
+
+    format!("The zone has these records: {}.", names.join(", "))
+}
+
+pub(crate) fn create_dns_record(
+    cloudflare: &CloudflareClient,
+    zone_id: &str,
+    record_name: &str,
+    ip: Ipv4Addr,
+    ttl: Option<u32>,
+    proxied: bool,
+) -> Result<DnsRecord, String> {
+    let request = dns::CreateDnsRecord {
+        zone_identifier: zone_id,
+        params: dns::CreateDnsRecordParams {
+            name: record_name,
+            content: DnsContent::A { content: ip },
+            ttl,
+            priority: None,
+            proxied: Some(proxied),
+        },
+    };
+
+    cloudflare
+        .request(&request)
+        .map_err(|err| {
+            format!(
+                "Failed to create DNS record: {}",
+                format_cloudflare_api_failure(err)
+            )
+        })
+        .map(|response| response.result)
+}
+
+/// Builds the parameters that would be sent to Cloudflare in order to update the record.
+///
+/// The update replaces the whole record, and Cloudflare resets any field that is left out to its
+/// default. The TTL and proxied setting are therefore always sent, using the current values of
+/// the record unless other ones are wanted.
+pub(crate) fn update_params<'a>(
+    options: &Options,
+    current_record: &'a DnsRecord,
+    new_ip: Ipv4Addr,
+) -> dns::UpdateDnsRecordParams<'a> {
+    dns::UpdateDnsRecordParams {
+        name: &current_record.name,
+        content: DnsContent::A { content: new_ip },
+        ttl: Some(options.wanted_ttl().unwrap_or(current_record.ttl)),
+        proxied: Some(options.wanted_proxied().unwrap_or(current_record.proxied)),
+    }
+}
+
+pub(crate) fn update_dns_record(
+    options: &Options,
+    cloudflare: &CloudflareClient,
+    zone_id: &str,
+    current_record: &DnsRecord,
+    new_ip: Ipv4Addr,
+) -> Result<DnsRecord, String> {
+    let request = dns::UpdateDnsRecord {
+        zone_identifier: zone_id,
+        identifier: &current_record.id,
+        params: update_params(options, current_record, new_ip),
+    };
+
+    cloudflare
+        .request(&request)
+        .map_err(|err| {
+            format!(
+                "Failed to update DNS record: {}",
+                format_cloudflare_api_failure(err)
+            )
+        })
+        .map(|response| response.result)
+}
+
+/// Prints a table of every field that an update manages, comparing the current record with what
+/// would be written.
+///
+/// Record comments are not listed since the `cloudflare` crate can neither read nor write them.
+pub(crate) fn print_update_preview(
+    current_record: &DnsRecord,
+    params: &dns::UpdateDnsRecordParams,
+) {
+    let new_content = format_dns_content(&params.content);
+    let new_ttl = params
+        .ttl
+        .expect("Programmer error: Updates always send the TTL");
+    let new_proxied = params
+        .proxied
+        .expect("Programmer error: Updates always send the proxied setting");
+
+    let rows = [
+        (
+            "content",
+            format_dns_content(&current_record.content),
+            new_content,
+        ),
+        ("ttl", format_ttl(current_record.ttl), format_ttl(new_ttl)),
+        (
+            "proxied",
+            current_record.proxied.to_string(),
+            new_proxied.to_string(),
+        ),
+    ];
+
+    let current_width = rows
+        .iter()
+        .map(|(_, current, _)| current.len())
+        .chain(Some("Current".len()))
+        .max()
+        .unwrap_or(0);
+    let new_width = rows
+        .iter()
+        .map(|(_, _, new)| new.len())
+        .chain(Some("New".len()))
+        .max()
+        .unwrap_or(0);
+
+    eprintln!(
+        "  {:<8} {:<current_width$} {:<new_width$}",
+        "Field",
+        "Current",
+        "New",
+        current_width = current_width,
+        new_width = new_width,
+    );
+    for (field, current, new) in rows.iter() {
+        eprintln!(
+            "  {:<8} {:<current_width$} {:<new_width$} {}",
+            field,
+            current,
+            new,
+            if current == new { "same" } else { "changed" },
+            current_width = current_width,
+            new_width = new_width,
+        );
+    }
+}
+
+pub(crate) fn format_dns_content(content: &DnsContent) -> String {
+    match content {
+        DnsContent::A { content } => format!("A {}", content),
+        DnsContent::AAAA { content } => format!("AAAA {}", content),
+        DnsContent::CNAME { content } => format!("CNAME {}", content),
+        DnsContent::NS { content } => format!("NS {}", content),
+        DnsContent::MX { content, priority } => format!("MX {} {}", priority, content),
+        DnsContent::TXT { content } => format!("TXT {}", content),
+        DnsContent::SRV { content } => format!("SRV {}", content),
+    }
+}
+
+pub(crate) fn format_ttl(ttl: u32) -> String {
+    // Cloudflare uses a TTL of 1 to mean "automatic".
+    if ttl == 1 {
+        String::from("auto")
+    } else {
+        ttl.to_string()
+    }
+}
+
+pub(crate) fn format_cloudflare_api_failure(failure: ApiFailure) -> String {
+    match failure {
+        ApiFailure::Error(status, errors) => format!(
+            "Status code {status}:\n  {errors}",
+            status = status,
+            errors = format_cloudflare_errors(errors),
+        ),
+        ApiFailure::Invalid(err) => err.to_string(),
+    }
+}
+
+fn format_cloudflare_errors(errors: ApiErrors) -> String {
+    errors
+        .errors
+        .iter()
+        .map(|error| format!("{}: {}", error.code, error.message))
+        .collect::<Vec<String>>()
+        .join("\n  ")
+}
@@ -0,0 +1,45 @@
+//! Colors for the messages written to stderr.
+
+use crate::options::{Options, OutputFormat};
+use std::env;
+use std::fmt::Display;
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+const GREEN: &str = "32";
+const YELLOW: &str = "33";
+const RED: &str = "31";
+
+/// Turns on colors for the messages on stderr, unless the options, the `NO_COLOR` environment
+/// variable or stderr not being a terminal says otherwise. Colors are off until this is called.
+pub fn init_color(options: &Options) {
+    let no_color_env = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    let enabled = !options.no_color
+        && !no_color_env
+        && options.output != OutputFormat::Json
+        && io::stderr().is_terminal();
+
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub(crate) fn paint_success(text: impl Display) -> String {
+    paint(GREEN, text)
+}
+
+pub fn paint_warning(text: impl Display) -> String {
+    paint(YELLOW, text)
+}
+
+pub fn paint_error(text: impl Display) -> String {
+    paint(RED, text)
+}
+
+fn paint(color: &str, text: impl Display) -> String {
+    if ENABLED.load(Ordering::Relaxed) {
+        format!("\x1b[{}m{}\x1b[0m", color, text)
+    } else {
+        text.to_string()
+    }
+}
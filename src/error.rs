@@ -0,0 +1,96 @@
+//! The error type returned by the library.
+
+use std::fmt;
+use std::net::Ipv4Addr;
+
+/// Exit code used when the detected IP is in the shared address space used by carrier-grade NAT.
+const EXIT_CGNAT: u8 = 3;
+/// Exit code used when the record was written, but reading it back did not show the new IP.
+const EXIT_UPDATE_NOT_VISIBLE: u8 = 4;
+/// Exit code used by --diff-only when the record does not point to the current IP, and by
+/// --exit-code-on-change when a dry run would change the record.
+const EXIT_RECORD_OUTDATED: u8 = 5;
+/// Exit code used when the record does not have the value given by --expected-current-ip.
+const EXIT_UNEXPECTED_RECORD: u8 = 6;
+/// Exit code used when --pre-update-command vetoed the update.
+const EXIT_VETOED: u8 = 7;
+
+/// Everything that can make an update fail.
+#[derive(Debug)]
+pub enum Error {
+    /// The detected IP is in the shared address space used by carrier-grade NAT, and private
+    /// addresses are not allowed.
+    CgnatAddress(Ipv4Addr),
+    /// The record was written, but reading it back kept returning something else than the IP.
+    UpdateNotVisible(Ipv4Addr),
+    /// The record does not point to the current IP, as found when only checking the record or
+    /// in a dry run.
+    RecordOutdated(String),
+    /// The record was about to be updated, but it did not have the expected value, so something
+    /// else might have changed it.
+    UnexpectedRecord {
+        expected: Ipv4Addr,
+        /// The current content of the record, or `None` if there is no record.
+        found: Option<String>,
+    },
+    /// The update was about to happen, but --pre-update-command exited unsuccessfully.
+    Vetoed(String),
+    /// Any other failure, described by the message.
+    Other(String),
+}
+
+impl Error {
+    /// The exit code that the command line program uses for this error.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::CgnatAddress(_) => EXIT_CGNAT,
+            Error::UpdateNotVisible(_) => EXIT_UPDATE_NOT_VISIBLE,
+            Error::RecordOutdated(_) => EXIT_RECORD_OUTDATED,
+            Error::UnexpectedRecord { .. } => EXIT_UNEXPECTED_RECORD,
+            Error::Vetoed(_) => EXIT_VETOED,
+            Error::Other(_) => 1,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::CgnatAddress(ip) => write!(
+                f,
+                "{ip} is in the shared address space used by carrier-grade NAT (100.64.0.0/10).\n\
+                 This is the address of your ISP's NAT, not an address of your own, so your \
+                 connection most likely cannot receive inbound connections at all.\n\
+                 Pass --allow-private if you want to write it to DNS anyway.",
+                ip = ip
+            ),
+            Error::UpdateNotVisible(ip) => write!(
+                f,
+                "The record was updated, but Cloudflare still did not return {} when reading it \
+                 back. The change might still be on its way.",
+                ip
+            ),
+            Error::UnexpectedRecord { expected, found } => write!(
+                f,
+                "Expected the record to point to {}, but {}. Something else might have changed \
+                 it, so it was left alone.",
+                expected,
+                match found {
+                    Some(content) => format!("it is {}", content),
+                    None => String::from("it does not exist"),
+                }
+            ),
+            Error::RecordOutdated(message) | Error::Vetoed(message) | Error::Other(message) => {
+                f.write_str(message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<String> for Error {
+    fn from(message: String) -> Error {
+        Error::Other(message)
+    }
+}
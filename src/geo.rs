@@ -0,0 +1,100 @@
+//! Checking the network and country of the detected IP against what the user expects, using
+//! ipinfo.io.
+
+use crate::color::paint_warning;
+use crate::ip::http_client;
+use crate::options::Options;
+use serde::Deserialize;
+use std::net::Ipv4Addr;
+
+/// What ipinfo.io knows about an IP. Only the fields that are checked are parsed.
+#[derive(Deserialize, Debug)]
+struct IpInfo {
+    country: Option<String>,
+    /// The AS number and name of the network, like "AS64500 Example ISP".
+    org: Option<String>,
+}
+
+/// Checks that the IP belongs to the expected AS and country, if any are given. Mismatches and
+/// failed lookups are only warnings unless `strict_geo` is set, since ipinfo.io is not always
+/// right or reachable.
+pub(crate) fn check_ip_origin(options: &Options, ip: Ipv4Addr) -> Result<(), String> {
+    if options.expect_asn.is_none() && options.expect_country.is_none() {
+        return Ok(());
+    }
+
+    let problem = match lookup_ip_info(options, ip) {
+        Ok(info) => find_mismatch(options, ip, &info),
+        Err(err) => Some(format!("Could not look up where {} belongs: {}", ip, err)),
+    };
+
+    match problem {
+        None => {
+            if options.is_verbose() {
+                eprintln!("{} belongs to the expected network", ip);
+            }
+            Ok(())
+        }
+        Some(problem) if options.strict_geo => Err(problem),
+        Some(problem) => {
+            eprintln!(
+                "{} {} Continuing since --strict-geo is not set.",
+                paint_warning("Warning:"),
+                problem
+            );
+            Ok(())
+        }
+    }
+}
+
+fn lookup_ip_info(options: &Options, ip: Ipv4Addr) -> Result<IpInfo, String> {
+    let url = options
+        .ipinfo_url
+        .join(&format!("{}/json", ip))
+        .map_err(|err| err.to_string())?;
+
+    let body = http_client(options)?
+        .get(url)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.text())
+        .map_err(|err| err.to_string())?;
+
+    serde_json::from_str(&body).map_err(|err| format!("Invalid reply: {}", err))
+}
+
+fn find_mismatch(options: &Options, ip: Ipv4Addr, info: &IpInfo) -> Option<String> {
+    if let Some(expected) = options.expect_asn {
+        let asn = info
+            .org
+            .as_deref()
+            .and_then(|org| org.split_whitespace().next())
+            .and_then(|asn| asn.strip_prefix("AS"))
+            .and_then(|asn| asn.parse::<u32>().ok());
+        if asn != Some(expected) {
+            return Some(format!(
+                "{} belongs to {}, not AS{}.",
+                ip,
+                info.org.as_deref().unwrap_or("an unknown network"),
+                expected
+            ));
+        }
+    }
+
+    if let Some(expected) = &options.expect_country {
+        if !info
+            .country
+            .as_deref()
+            .is_some_and(|country| country.eq_ignore_ascii_case(expected))
+        {
+            return Some(format!(
+                "{} is in {}, not {}.",
+                ip,
+                info.country.as_deref().unwrap_or("an unknown country"),
+                expected.to_uppercase()
+            ));
+        }
+    }
+
+    None
+}
@@ -0,0 +1,97 @@
+//! Checks that a service is reachable at the new IP before the record is pointed at it.
+
+use crate::options::Options;
+use reqwest::blocking::ClientBuilder;
+use reqwest::Url;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+/// A service that must answer at the new IP for the record to be updated.
+#[derive(Clone, Debug)]
+pub enum HealthCheck {
+    /// Something must accept TCP connections on the port.
+    Tcp(u16),
+    /// A GET request to the URL must succeed. The host of the URL is resolved to the new IP.
+    Http(Url),
+}
+
+impl fmt::Display for HealthCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HealthCheck::Tcp(port) => write!(f, "TCP port {}", port),
+            HealthCheck::Http(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+/// Parses "tcp:PORT", or "http:URL" where the "http:" prefix may be left out of a full URL.
+pub(crate) fn parse_health_check(string: &str) -> Result<HealthCheck, String> {
+    if let Some(port) = string.strip_prefix("tcp:") {
+        return port
+            .parse()
+            .map(HealthCheck::Tcp)
+            .map_err(|err| format!("Invalid port {}: {}", port, err));
+    }
+
+    // "http:http://…" and "http://…" are both accepted.
+    let url = string
+        .strip_prefix("http:")
+        .and_then(|rest| rest.parse::<Url>().ok())
+        .map_or_else(|| string.parse::<Url>(), Ok)
+        .map_err(|err| {
+            format!(
+                "Invalid health check {}: {}. Use tcp:PORT or http:URL.",
+                string, err
+            )
+        })?;
+
+    match url.scheme() {
+        "http" | "https" => Ok(HealthCheck::Http(url)),
+        scheme => Err(format!(
+            "Invalid health check {}: Unsupported scheme {}",
+            string, scheme
+        )),
+    }
+}
+
+/// Checks that the service answers at the IP, returning why not if it does not.
+pub(crate) fn run_health_check(
+    options: &Options,
+    check: &HealthCheck,
+    ip: Ipv4Addr,
+) -> Result<(), String> {
+    let timeout = Duration::from_secs(options.health_check_timeout.into());
+
+    match check {
+        HealthCheck::Tcp(port) => {
+            TcpStream::connect_timeout(&SocketAddr::new(IpAddr::V4(ip), *port), timeout)
+                .map(|_| ())
+                .map_err(|err| err.to_string())
+        }
+        HealthCheck::Http(url) => {
+            let mut url = url.clone();
+            let port = url.port_or_known_default().unwrap_or(80);
+            let mut builder = ClientBuilder::new().timeout(timeout);
+            // A host name is kept so that the request has the right Host header and TLS
+            // certificate, while an IP address is replaced.
+            match url.domain() {
+                Some(domain) => {
+                    builder = builder.resolve(domain, SocketAddr::new(IpAddr::V4(ip), port))
+                }
+                None => url
+                    .set_ip_host(IpAddr::V4(ip))
+                    .expect("Programmer error: HTTP URLs always have a host"),
+            }
+
+            builder
+                .build()
+                .map_err(|err| format!("Failed to construct HTTP client: {}", err))?
+                .get(url)
+                .send()
+                .and_then(|response| response.error_for_status())
+                .map(|_| ())
+                .map_err(|err| err.without_url().to_string())
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! The history file, which keeps a log of every update made to the record.
+
+use crate::color::paint_warning;
+use crate::options::Options;
+use chrono::{SecondsFormat, Utc};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+
+/// The history file is rotated once it grows beyond this size.
+const HISTORY_MAX_BYTES: u64 = 1024 * 1024;
+
+/// Adds an entry to the history file, if one is used. Failures are only reported as warnings
+/// since the update itself already went through.
+pub(crate) fn write_history(options: &Options, record: &str, old_value: &str, new_ip: Ipv4Addr) {
+    if let Some(path) = &options.history_file {
+        let entry = HistoryEntry {
+            time: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            record: record.to_string(),
+            old_value: old_value.to_string(),
+            new_value: new_ip.to_string(),
+        };
+        if let Err(err) = append_history(path, &entry) {
+            eprintln!(
+                "{} Failed to write to history file: {}",
+                paint_warning("Warning:"),
+                err
+            );
+        }
+    }
+}
+
+/// A single update, as stored in the history file.
+struct HistoryEntry {
+    time: String,
+    record: String,
+    old_value: String,
+    new_value: String,
+}
+
+impl HistoryEntry {
+    fn to_line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\n",
+            self.time, self.record, self.old_value, self.new_value
+        )
+    }
+
+    fn from_line(line: &str) -> Option<HistoryEntry> {
+        let mut fields = line.split('\t');
+        let entry = HistoryEntry {
+            time: fields.next()?.to_string(),
+            record: fields.next()?.to_string(),
+            old_value: fields.next()?.to_string(),
+            new_value: fields.next()?.to_string(),
+        };
+
+        if fields.next().is_some() {
+            None
+        } else {
+            Some(entry)
+        }
+    }
+}
+
+fn rotated_history_path(path: &Path) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(".1");
+    PathBuf::from(rotated)
+}
+
+/// Appends the entry to the history file as a single write, and syncs it to disk. A crash can
+/// therefore at worst leave a partial last line, which is ignored when reading the history.
+fn append_history(path: &Path, entry: &HistoryEntry) -> Result<(), String> {
+    let size = fs::metadata(path)
+        .map(|metadata| metadata.len())
+        .unwrap_or(0);
+    if size >= HISTORY_MAX_BYTES {
+        fs::rename(path, rotated_history_path(path))
+            .map_err(|err| format!("Failed to rotate {}: {}", path.display(), err))?;
+    }
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|err| format!("Failed to open {}: {}", path.display(), err))?;
+
+    file.write_all(entry.to_line().as_bytes())
+        .and_then(|_| file.sync_data())
+        .map_err(|err| format!("Failed to write {}: {}", path.display(), err))
+}
+
+fn read_history(path: &Path) -> Result<Vec<HistoryEntry>, String> {
+    let mut entries = Vec::new();
+
+    for path in [rotated_history_path(path), path.to_owned()].iter() {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(format!("Failed to read {}: {}", path.display(), err)),
+        };
+
+        entries.extend(contents.lines().filter_map(HistoryEntry::from_line));
+    }
+
+    Ok(entries)
+}
+
+/// Prints the most recent entries from the history file on stdout.
+pub fn print_history(options: &Options, limit: usize) -> Result<(), String> {
+    let path = options
+        .history_file
+        .as_ref()
+        .ok_or_else(|| "No history file given. Use --history-file to set it.".to_string())?;
+
+    let entries = read_history(path)?;
+    let skip = entries.len().saturating_sub(limit);
+
+    for entry in entries.iter().skip(skip) {
+        println!(
+            "{}  {}  {} -> {}",
+            entry.time, entry.record, entry.old_value, entry.new_value
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,64 @@
+//! Runs the command from `--pre-update-command`, which can veto an update.
+
+use crate::error::Error;
+use crate::options::Options;
+use std::net::Ipv4Addr;
+use std::process::Command;
+
+/// Runs `--pre-update-command` right before the record is written. The update may go ahead if
+/// the command exits successfully, and is vetoed with `Error::Vetoed` otherwise.
+///
+/// The command gets the record in `DYNDNS_RECORD`, its current value in `DYNDNS_OLD_IP` (empty
+/// when it is about to be created) and the detected IP in `DYNDNS_NEW_IP`.
+pub(crate) fn run_pre_update_command(
+    options: &Options,
+    record_name: &str,
+    old_ip: &str,
+    new_ip: Ipv4Addr,
+) -> Result<(), Error> {
+    let command = match &options.pre_update_command {
+        Some(command) => command,
+        None => return Ok(()),
+    };
+
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let output = Command::new(shell)
+        .args([flag, command])
+        .env("DYNDNS_RECORD", record_name)
+        .env("DYNDNS_OLD_IP", old_ip)
+        .env("DYNDNS_NEW_IP", new_ip.to_string())
+        .output()
+        .map_err(|err| format!("Failed to run --pre-update-command {}: {}", command, err))?;
+
+    if output.status.success() {
+        if options.is_verbose() {
+            eprintln!("--pre-update-command allowed the update");
+        }
+        return Ok(());
+    }
+
+    // What the command printed is the best explanation of why it said no.
+    let reason = [&output.stderr, &output.stdout]
+        .iter()
+        .map(|text| String::from_utf8_lossy(text).trim().to_string())
+        .find(|text| !text.is_empty());
+    let status = match output.status.code() {
+        Some(code) => format!("exited with status {}", code),
+        None => String::from("was killed by a signal"),
+    };
+
+    Err(Error::Vetoed(match reason {
+        Some(reason) => format!(
+            "--pre-update-command {}, so {} was left alone: {}",
+            status, record_name, reason
+        ),
+        None => format!(
+            "--pre-update-command {}, so {} was left alone.",
+            status, record_name
+        ),
+    }))
+}
@@ -0,0 +1,674 @@
+//! Finding the external IP by asking public IP services.
+
+use crate::color::paint_warning;
+use crate::options::{Options, SelectStrategy, Verbosity};
+use crate::source::{HttpEchoSource, IpSource, SourceError, UpnpSource};
+use reqwest::blocking::{Client, ClientBuilder};
+use reqwest::Url;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::path::Path;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const IP_SERVICE_URLS: [&str; 7] = [
+    // HTTPS sources
+    "https://checkip.amazonaws.com/",
+    "https://httpbin.org/ip",
+    "https://icanhazip.com/",
+    "https://ipecho.net/plain",
+    "https://ipinfo.io/ip",
+    // HTTP sources
+    "http://checkip.dyndns.com/",
+    "http://whatismyip.akamai.com/",
+];
+/// How many services the "fastest" select strategy races against each other.
+const FASTEST_RACE_SIZE: usize = 3;
+/// How many services need to agree for the "consensus-lite" select strategy.
+const CONSENSUS_LITE_QUORUM: usize = 3;
+
+pub(crate) fn http_client(options: &Options) -> Result<Client, String> {
+    if let Some(address) = options.source_addr {
+        // Binding to the address is the simplest way to check that it belongs to this host, which
+        // gives a much clearer error than the failed requests would.
+        UdpSocket::bind((address, 0)).map_err(|err| {
+            format!(
+                "Cannot use source address {}: {}. Is it assigned to this host?",
+                address, err
+            )
+        })?;
+    }
+
+    options
+        .cloudflare
+        .resolve_hosts(ClientBuilder::new())
+        .timeout(Duration::from_secs(options.ip_timeout.into()))
+        .local_address(options.source_addr)
+        .build()
+        .map_err(|error| format!("Failed to construct HTTP client: {}", error))
+}
+
+/// Returns the list of IP services to query, either from `--ip-services-file` or the built-in
+/// list.
+pub fn ip_services(options: &Options) -> Result<Vec<Url>, String> {
+    let services = match &options.ip_services_file {
+        Some(path) => load_ip_services_file(path)?,
+        None => IP_SERVICE_URLS
+            .iter()
+            .map(|url| {
+                url.parse()
+                    .expect("Programmer error: Invalid IP service URL")
+            })
+            .collect(),
+    };
+
+    // A service that is listed twice would get two votes with --verify. The URLs are already
+    // normalized by parsing, so "HTTP://Example.com" and "http://example.com/" are the same.
+    let mut unique_services: Vec<Url> = Vec::with_capacity(services.len());
+    for url in services {
+        if unique_services.contains(&url) {
+            if options.is_verbose() {
+                eprintln!("Ignoring duplicate IP service {}", url);
+            }
+        } else {
+            unique_services.push(url);
+        }
+    }
+
+    Ok(unique_services)
+}
+
+fn load_ip_services_file(path: &Path) -> Result<Vec<Url>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+
+    let services = contents
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(line_number, line)| {
+            parse_ip_service_url(line)
+                .map_err(|err| format!("{}:{}: {}", path.display(), line_number, err))
+        })
+        .collect::<Result<Vec<Url>, String>>()?;
+
+    if services.is_empty() {
+        return Err(format!("No IP services listed in {}", path.display()));
+    }
+
+    Ok(services)
+}
+
+fn parse_ip_service_url(string: &str) -> Result<Url, String> {
+    let url: Url = string
+        .parse()
+        .map_err(|err| format!("Invalid URL {}: {}", string, err))?;
+
+    match url.scheme() {
+        "http" | "https" => Ok(url),
+        scheme => Err(format!(
+            "Invalid URL {}: Unsupported scheme {}",
+            string, scheme
+        )),
+    }
+}
+
+/// How each source that was asked answered, by name and in the order that they answered.
+pub(crate) type SourceResults = Vec<(String, Answer)>;
+
+/// How a source answered, as far as its health is concerned.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Answer {
+    Ip(Ipv4Addr),
+    Failed,
+    RateLimited,
+}
+
+impl Answer {
+    fn of(reply: &Result<Ipv4Addr, SourceError>) -> Answer {
+        match reply {
+            Ok(ip) => Answer::Ip(*ip),
+            Err(SourceError::RateLimited(_)) => Answer::RateLimited,
+            Err(_) => Answer::Failed,
+        }
+    }
+}
+
+/// Returns the sources to ask for the external IP, as the options ask for. With `upnp` only the
+/// router is asked, unless `verify` lets it vote alongside the IP services.
+pub fn ip_sources(options: &Options, services: &[Url]) -> Result<Vec<Arc<dyn IpSource>>, String> {
+    let mut sources: Vec<Arc<dyn IpSource>> = Vec::new();
+
+    if !options.upnp || options.verify {
+        for url in services {
+            sources.push(Arc::new(HttpEchoSource::new(options, url.clone())?));
+        }
+    }
+    if options.upnp {
+        sources.push(Arc::new(UpnpSource::new(options)));
+    }
+
+    Ok(sources)
+}
+
+/// Finds the external IP using the services, in the way that the options ask for.
+pub fn determine_external_ip(options: &Options, services: &[Url]) -> Result<Ipv4Addr, String> {
+    let sources = ip_sources(options, services)?;
+    determine_external_ip_from_sources(options, &sources)
+}
+
+/// Finds the external IP by asking the sources, in the way that the options ask for.
+pub fn determine_external_ip_from_sources(
+    options: &Options,
+    sources: &[Arc<dyn IpSource>],
+) -> Result<Ipv4Addr, String> {
+    determine_external_ip_with_results(options, sources, &mut Vec::new())
+}
+
+/// Like `determine_external_ip_from_sources`, but also keeps track of which sources answered.
+pub(crate) fn determine_external_ip_with_results(
+    options: &Options,
+    sources: &[Arc<dyn IpSource>],
+    results: &mut SourceResults,
+) -> Result<Ipv4Addr, String> {
+    if options.verify {
+        return determine_external_ip_with_verification(options, sources, results);
+    }
+
+    match options.select_strategy {
+        SelectStrategy::First => {
+            determine_external_ip_without_verification(options, sources, results)
+        }
+        SelectStrategy::Fastest => determine_external_ip_from_fastest(options, sources, results),
+        SelectStrategy::ConsensusLite => {
+            determine_external_ip_with_quorum(options, sources, results)
+        }
+    }
+}
+
+fn determine_external_ip_without_verification(
+    options: &Options,
+    sources: &[Arc<dyn IpSource>],
+    results: &mut SourceResults,
+) -> Result<Ipv4Addr, String> {
+    if shows_progress(options) {
+        eprint!("Retreiving external IP… ");
+    }
+
+    query_sources_in_order(options, sources, results).ok_or_else(|| {
+        format!(
+            "None of the {} service(s) replied successfully.",
+            sources.len()
+        )
+    })
+}
+
+/// Whether to show the single line of progress that replaces the details of --verbose.
+fn shows_progress(options: &Options) -> bool {
+    options.verbosity() == Verbosity::Normal
+}
+
+/// Asks the sources one at a time and returns the first IP that is found.
+fn query_sources_in_order(
+    options: &Options,
+    sources: &[Arc<dyn IpSource>],
+    results: &mut SourceResults,
+) -> Option<Ipv4Addr> {
+    for source in sources {
+        // The prefix is written after asking the source, since it might log the reply.
+        let reply = source.fetch_ip();
+        if options.is_verbose() {
+            eprint!("{} -> ", source.name());
+        }
+        results.push((source.name(), Answer::of(&reply)));
+
+        match reply {
+            Ok(ip) => {
+                if !options.is_quiet() {
+                    eprintln!("{}", ip);
+                }
+                return Some(ip);
+            }
+            Err(err) => {
+                if options.is_verbose() {
+                    print_source_failure(options, &err);
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// Races the first few sources against each other and uses the first IP that comes back. If
+/// none of them find an IP, the rest of the sources are asked one at a time.
+fn determine_external_ip_from_fastest(
+    options: &Options,
+    sources: &[Arc<dyn IpSource>],
+    results: &mut SourceResults,
+) -> Result<Ipv4Addr, String> {
+    let (racers, rest) = sources.split_at(sources.len().min(FASTEST_RACE_SIZE));
+
+    if shows_progress(options) {
+        eprint!("Retreiving external IP… ");
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    for source in racers {
+        let sender = sender.clone();
+        let source = Arc::clone(source);
+        // The threads are never joined, so a slow source cannot hold up the result. They are
+        // bounded by the request timeout anyway.
+        thread::spawn(move || {
+            let reply = source.fetch_ip();
+            // The receiver is gone if another source already won the race.
+            let _ = sender.send((source.name(), reply));
+        });
+    }
+    drop(sender);
+
+    for (name, reply) in receiver {
+        if options.is_verbose() {
+            eprint!("{} -> ", name);
+        }
+        results.push((name, Answer::of(&reply)));
+
+        match reply {
+            Ok(ip) => {
+                if !options.is_quiet() {
+                    eprintln!("{}", ip);
+                }
+                return Ok(ip);
+            }
+            Err(err) => {
+                if options.is_verbose() {
+                    print_source_failure(options, &err);
+                }
+            }
+        }
+    }
+
+    query_sources_in_order(options, rest, results).ok_or_else(|| {
+        format!(
+            "None of the {} service(s) replied successfully.",
+            sources.len()
+        )
+    })
+}
+
+/// Asks sources one at a time until a small quorum of them have answered, and requires all of
+/// them to agree on the IP.
+fn determine_external_ip_with_quorum(
+    options: &Options,
+    sources: &[Arc<dyn IpSource>],
+    results: &mut SourceResults,
+) -> Result<Ipv4Addr, String> {
+    let quorum = sources.len().min(CONSENSUS_LITE_QUORUM);
+    let mut answers: Vec<Ipv4Addr> = Vec::with_capacity(quorum);
+
+    if shows_progress(options) {
+        eprint!("Retreiving external IP from {} services… ", quorum);
+    }
+
+    for source in sources {
+        if answers.len() == quorum {
+            break;
+        }
+
+        let reply = source.fetch_ip();
+        if options.is_verbose() {
+            eprint!("{} -> ", source.name());
+        }
+        results.push((source.name(), Answer::of(&reply)));
+
+        match reply {
+            Ok(ip) => {
+                if options.is_verbose() {
+                    eprintln!("{}", ip);
+                }
+                answers.push(ip);
+            }
+            Err(err) => {
+                if options.is_verbose() {
+                    print_source_failure(options, &err);
+                }
+            }
+        }
+    }
+
+    if answers.len() < quorum {
+        if shows_progress(options) {
+            eprintln!("Failed");
+        }
+        return Err(format!(
+            "Only {} of the {} service(s) replied successfully, but {} are needed for a quorum.",
+            answers.len(),
+            sources.len(),
+            quorum
+        ));
+    }
+
+    let ip = answers[0];
+    if answers.iter().all(|answer| *answer == ip) {
+        if options.is_verbose() {
+            eprintln!("The quorum agrees on {}", ip);
+        } else if shows_progress(options) {
+            eprintln!("{}", ip);
+        }
+        Ok(ip)
+    } else {
+        if shows_progress(options) {
+            eprintln!("Failed");
+        }
+        let answers: Vec<String> = answers.iter().map(Ipv4Addr::to_string).collect();
+        Err(format!(
+            "Services disagree on the IP ({}). Use --verify to let all services vote on it.",
+            answers.join(", ")
+        ))
+    }
+}
+
+fn determine_external_ip_with_verification(
+    options: &Options,
+    sources: &[Arc<dyn IpSource>],
+    results: &mut SourceResults,
+) -> Result<Ipv4Addr, String> {
+    let threshold = options.verify_threshold;
+
+    let mut votes: HashMap<String, u16> = HashMap::new();
+
+    let longest_name_length = sources
+        .iter()
+        .map(|source| source.name().len())
+        .max()
+        .unwrap_or(10);
+
+    if shows_progress(options) {
+        eprint!("Retreiving and validating external IP… ");
+    }
+
+    let concurrency = usize::from(options.max_concurrent_ip_requests).min(sources.len());
+    if options.verbosity() >= Verbosity::Debug {
+        eprintln!("Asking up to {} service(s) at a time", concurrency);
+    }
+
+    // Up to --max-concurrent-ip-requests sources are asked at the same time, and the next one is
+    // started whenever one of them answers. The threads are never joined so that the vote can end
+    // as soon as the outcome is certain. They are bounded by the request timeout anyway.
+    let (sender, receiver) = mpsc::channel();
+    let mut sender = Some(sender);
+    let mut queued = sources.iter();
+    let mut start_next = |sender: &mut Option<mpsc::Sender<_>>| match queued.next() {
+        Some(source) => {
+            let sender = sender
+                .clone()
+                .expect("Programmer error: Sender is only dropped when all sources are started");
+            let source = Arc::clone(source);
+            thread::spawn(move || {
+                let start = Instant::now();
+                let reply = source.fetch_ip();
+                let _ = sender.send((source.name(), start.elapsed(), reply));
+            });
+        }
+        // Without the last sender, the loop below ends when every source has answered.
+        None => *sender = None,
+    };
+    // Without any sources, the first call drops the sender so that the loop below ends.
+    for _ in 0..concurrency.max(1) {
+        start_next(&mut sender);
+    }
+    let mut pending = sources.len() as u16;
+
+    for (name, elapsed, reply) in receiver {
+        pending -= 1;
+
+        if options.is_verbose() {
+            eprint!(
+                "{0:>1$} -> [{2:>5} ms] ",
+                name,
+                longest_name_length,
+                elapsed.as_millis()
+            );
+            match &reply {
+                Ok(ip) => eprintln!("{}", ip),
+                Err(err) => print_source_failure(options, err),
+            }
+        }
+
+        results.push((name, Answer::of(&reply)));
+        if let Ok(ip) = reply {
+            *votes.entry(ip.to_string()).or_insert(0) += 1;
+        }
+
+        if pending > 0 {
+            if let Some(ip) = early_verify_winner(&votes, pending, threshold) {
+                if options.is_verbose() {
+                    eprintln!(
+                        "{} has enough of the votes no matter what the remaining {} service(s) \
+                         answer",
+                        ip, pending
+                    );
+                } else if shows_progress(options) {
+                    eprintln!("Done");
+                }
+                return parse_ip(ip);
+            }
+        }
+
+        // Only after the early exit, so that no service is asked once the vote is decided.
+        start_next(&mut sender);
+    }
+
+    match votes.len() {
+        0 => Err("All of the services failed.".to_string()),
+        1 => {
+            let ip = votes.keys().next().unwrap();
+            if options.is_verbose() {
+                eprintln!("All services agree on {}", ip);
+            } else if shows_progress(options) {
+                eprintln!("Done");
+            }
+            parse_ip(ip)
+        }
+        _ => {
+            eprintln!(
+                "{} Some services disagree on IP!",
+                paint_warning("Warning:")
+            );
+            let total_votes: u16 = votes.values().copied().sum();
+            let top_vote = votes.iter().max_by_key(|(_ip, tally)| *tally).unwrap();
+            if has_enough_votes(*top_vote.1, total_votes, threshold) {
+                eprintln!(
+                    "IP {ip} has enough of the votes ({tally} of {total})",
+                    ip = top_vote.0,
+                    tally = top_vote.1,
+                    total = total_votes
+                );
+                parse_ip(top_vote.0)
+            } else {
+                let share = match threshold {
+                    Some(threshold) => format!("{:.0}%", threshold * 100.0),
+                    None => String::from("2/3"),
+                };
+                eprintln!("No IP has at least {} of the votes:", share);
+                for (ip, tally) in votes.iter() {
+                    eprintln!("  {}: {}", ip, tally);
+                }
+                eprintln!("Aborting.");
+                Err("Could not determine IP.".to_string())
+            }
+        }
+    }
+}
+
+/// Returns the IP that has won a `--verify` vote, if the outcome cannot change no matter what the
+/// `pending` sources answer.
+///
+/// This is conservative: the pending sources are assumed to all answer, and to all vote for the
+/// strongest competitor or for an IP that has not been seen yet, whichever hurts the most.
+///
+/// `threshold` is the value of --verify-threshold, see `has_enough_votes` for what happens
+/// without it.
+pub fn early_verify_winner(
+    votes: &HashMap<String, u16>,
+    pending: u16,
+    threshold: Option<f64>,
+) -> Option<&str> {
+    let mut tallies: Vec<(&str, u16)> = votes
+        .iter()
+        .map(|(ip, tally)| (ip.as_str(), *tally))
+        .collect();
+    tallies.sort_by_key(|(_, tally)| Reverse(*tally));
+
+    let (ip, top) = *tallies.first()?;
+    let runner_up = tallies.get(1).map(|(_, tally)| *tally).unwrap_or(0);
+    let largest_total = votes.values().sum::<u16>() + pending;
+
+    let cannot_be_caught = top > runner_up + pending;
+
+    if cannot_be_caught && has_enough_votes(top, largest_total, threshold) {
+        Some(ip)
+    } else {
+        None
+    }
+}
+
+/// Whether `tally` of the `total` votes is enough for an IP to be accepted with --verify.
+///
+/// Without --verify-threshold the IP needs 2/3 of the votes, rounded down like it always has
+/// been, so 2 of 4 or 4 of 7 votes are enough. A threshold that is given is used as is.
+fn has_enough_votes(tally: u16, total: u16, threshold: Option<f64>) -> bool {
+    match threshold {
+        Some(threshold) => f64::from(tally) >= f64::from(total) * threshold,
+        None => u32::from(tally) >= u32::from(total) * 2 / 3,
+    }
+}
+
+/// A range of IPv4 addresses in CIDR notation, like "192.0.2.0/24".
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ipv4Range {
+    network: Ipv4Addr,
+    prefix_length: u8,
+}
+
+impl Ipv4Range {
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        let mask = u32::MAX
+            .checked_shl(32 - u32::from(self.prefix_length))
+            .unwrap_or(0);
+        u32::from(ip) & mask == u32::from(self.network) & mask
+    }
+}
+
+pub(crate) fn parse_ipv4_range(string: &str) -> Result<Ipv4Range, String> {
+    let invalid = || {
+        format!(
+            "Invalid range {:?}. Use an IPv4 address and a prefix length, like 192.0.2.0/24.",
+            string
+        )
+    };
+
+    let (network, prefix_length) = string.trim().split_once('/').ok_or_else(invalid)?;
+    let network: Ipv4Addr = network.parse().map_err(|_| invalid())?;
+    let prefix_length: u8 = prefix_length.parse().map_err(|_| invalid())?;
+    if prefix_length > 32 {
+        return Err(invalid());
+    }
+
+    Ok(Ipv4Range {
+        network,
+        prefix_length,
+    })
+}
+
+fn parse_ip(string: &str) -> Result<Ipv4Addr, String> {
+    string
+        .parse()
+        .map_err(|err| format!("Failed to parse IP address {}: {}", string, err))
+}
+
+fn print_source_failure(options: &Options, err: &SourceError) {
+    eprintln!("Failed. {}", err);
+    if let SourceError::HttpStatus(_, snippet) = err {
+        if options.verbosity() >= Verbosity::Trace && !snippet.is_empty() {
+            eprintln!("  Response body: {}", snippet);
+        }
+    }
+}
+
+/// Checks if the IP is in the shared address space from RFC 6598 (100.64.0.0/10), which ISPs use
+/// behind carrier-grade NAT.
+pub fn is_cgnat_address(ip: Ipv4Addr) -> bool {
+    let [first, second, _, _] = ip.octets();
+    first == 100 && (second & 0b1100_0000) == 64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(string: &str) -> Ipv4Range {
+        parse_ipv4_range(string).unwrap()
+    }
+
+    #[test]
+    fn a_range_of_length_zero_contains_everything() {
+        let everything = range("0.0.0.0/0");
+        assert!(everything.contains(Ipv4Addr::new(0, 0, 0, 0)));
+        assert!(everything.contains(Ipv4Addr::new(203, 0, 113, 7)));
+        assert!(everything.contains(Ipv4Addr::new(255, 255, 255, 255)));
+    }
+
+    #[test]
+    fn a_range_of_length_32_contains_only_its_address() {
+        let single = range("192.0.2.1/32");
+        assert!(single.contains(Ipv4Addr::new(192, 0, 2, 1)));
+        assert!(!single.contains(Ipv4Addr::new(192, 0, 2, 0)));
+        assert!(!single.contains(Ipv4Addr::new(192, 0, 2, 2)));
+    }
+
+    #[test]
+    fn a_range_contains_the_addresses_within_its_prefix() {
+        let network = range("192.0.2.0/24");
+        assert!(network.contains(Ipv4Addr::new(192, 0, 2, 0)));
+        assert!(network.contains(Ipv4Addr::new(192, 0, 2, 255)));
+        assert!(!network.contains(Ipv4Addr::new(192, 0, 1, 255)));
+        assert!(!network.contains(Ipv4Addr::new(192, 0, 3, 0)));
+    }
+
+    #[test]
+    fn the_host_bits_of_the_base_address_are_ignored() {
+        let network = range("192.0.2.77/24");
+        assert!(network.contains(Ipv4Addr::new(192, 0, 2, 0)));
+        assert!(network.contains(Ipv4Addr::new(192, 0, 2, 200)));
+        assert!(!network.contains(Ipv4Addr::new(192, 0, 3, 77)));
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        for input in [
+            "192.0.2.0/33",
+            "192.0.2.0/255",
+            "192.0.2.0/-1",
+            "192.0.2.0/",
+            "192.0.2.0",
+            "192.0.2/24",
+            "/24",
+            "2001:db8::/32",
+        ] {
+            assert!(parse_ipv4_range(input).is_err(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn detects_the_edges_of_the_cgnat_range() {
+        assert!(!is_cgnat_address(Ipv4Addr::new(100, 63, 255, 255)));
+        assert!(is_cgnat_address(Ipv4Addr::new(100, 64, 0, 0)));
+        assert!(is_cgnat_address(Ipv4Addr::new(100, 127, 255, 255)));
+        assert!(!is_cgnat_address(Ipv4Addr::new(100, 128, 0, 0)));
+    }
+}
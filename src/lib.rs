@@ -0,0 +1,1118 @@
+//! Keeps a Cloudflare DNS record pointed at the external IP of the current host.
+//!
+//! The [`Options`] are the same as the command line options of the `cloudflare-dyndns-rs`
+//! binary, and [`DynDns`] runs an update with them. Progress and warnings are written to stderr,
+//! just like when running the binary.
+
+mod api;
+mod color;
+mod error;
+mod geo;
+mod health_check;
+mod history;
+mod hook;
+mod ip;
+mod notify;
+mod options;
+mod probe;
+mod provider;
+mod pushgateway;
+mod source;
+mod state;
+mod summary;
+mod upnp;
+mod version;
+
+use crate::api::{
+    create_dns_record, describe_zone_records, fetch_current_dns_record,
+    fetch_dns_records_with_suffix, find_zone_id, format_dns_content, format_ttl, preflight,
+    print_update_preview, update_dns_record, update_params,
+};
+use crate::color::paint_success;
+use crate::geo::check_ip_origin;
+use crate::health_check::run_health_check;
+use crate::history::write_history;
+use crate::hook::run_pre_update_command;
+use crate::ip::{determine_external_ip_with_results, http_client, Answer};
+use crate::notify::send_notification;
+use crate::state::{
+    load_state, save_state, seconds_since, unix_timestamp, write_atomically, State,
+};
+use crate::summary::Summary;
+use chrono::{SecondsFormat, Utc};
+use cloudflare::endpoints::dns::DnsContent;
+use cloudflare::framework::HttpApiClient as CloudflareClient;
+use reqwest::Url;
+use std::cmp::Ordering;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How many times to read the record back with `--verify-after-update`.
+const VERIFY_AFTER_UPDATE_ATTEMPTS: u32 = 3;
+/// How long to wait before each attempt to read the record back.
+const VERIFY_AFTER_UPDATE_DELAY: Duration = Duration::from_secs(2);
+
+pub use crate::api::{list_records, saas_origin, verify_token};
+pub use crate::color::{init_color, paint_error, paint_warning};
+pub use crate::error::Error;
+pub use crate::health_check::HealthCheck;
+pub use crate::history::print_history;
+pub use crate::ip::{
+    determine_external_ip, determine_external_ip_from_sources, early_verify_winner, ip_services,
+    ip_sources, is_cgnat_address, Ipv4Range,
+};
+pub use crate::options::{
+    CloudflareOptions, Command, HealthCheckFailure, HostOverride, IpFileFormat, Options,
+    OutputFormat, RecordPatch, SelectStrategy, TokenCommand, Verbosity, ZoneOptions,
+};
+pub use crate::probe::probe_services;
+pub use crate::provider::{DnsProvider, HttpProvider};
+pub use crate::pushgateway::push_metrics;
+pub use crate::source::{HttpEchoSource, IpSource, SourceError, UpnpSource};
+pub use crate::version::print_version;
+pub use cloudflare::endpoints::dns::DnsRecord;
+
+/// The result of a successful update cycle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Outcome {
+    /// The external IP that the record points to.
+    pub ip: Ipv4Addr,
+    /// Whether the record was written to.
+    pub updated: bool,
+    /// Whether the record needed to be changed. Unlike `updated`, this is also set in dry runs.
+    pub needs_update: bool,
+}
+
+/// Updates a DNS record to point to the external IP, as configured by the options.
+pub struct DynDns<'a> {
+    options: &'a Options,
+    cloudflare: CloudflareClient,
+    sources: Vec<Arc<dyn IpSource>>,
+    state: State,
+    providers: Vec<Box<dyn DnsProvider>>,
+    summary: Summary,
+    /// The zone ID, once it has been looked up.
+    zone_id: Option<String>,
+}
+
+impl<'a> DynDns<'a> {
+    /// Checks the options, loads the state file and sets up the Cloudflare client. No requests
+    /// are sent yet.
+    pub fn new(options: &'a Options) -> Result<DynDns<'a>, Error> {
+        if options.ip_timeout == 0 {
+            return Err(Error::from(String::from(
+                "A timeout of 0 seconds would mean no request could ever work.",
+            )));
+        }
+
+        let sources = ip_sources(options, &ip_services(options)?)?;
+        let state = match &options.state_file {
+            Some(path) => load_state(path)?,
+            None => State::default(),
+        };
+        let cloudflare = options.cloudflare.client()?;
+        let providers = options
+            .also_update_urls
+            .iter()
+            .map(|template| {
+                HttpProvider::new(options, template)
+                    .map(|provider| Box::new(provider) as Box<dyn DnsProvider>)
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(DynDns {
+            options,
+            cloudflare,
+            sources,
+            state,
+            providers,
+            summary: Summary::default(),
+            zone_id: None,
+        })
+    }
+
+    pub fn options(&self) -> &Options {
+        self.options
+    }
+
+    /// Adds another source to ask for the external IP, after the ones from the options.
+    pub fn add_ip_source(&mut self, source: Arc<dyn IpSource>) {
+        self.sources.push(source);
+    }
+
+    /// Adds another DNS provider that gets the new IP whenever the Cloudflare record is updated.
+    pub fn add_provider(&mut self, provider: Box<dyn DnsProvider>) {
+        self.providers.push(provider);
+    }
+
+    /// Runs a full update cycle. With `verbose` or `summary`, a recap of the run is printed at
+    /// the end.
+    pub fn run(&mut self) -> Result<Outcome, Error> {
+        let start = Instant::now();
+        let result = self.run_cycle();
+
+        if self.options.is_verbose() || self.options.summary {
+            if let Ok(outcome) = &result {
+                self.summary.ip = Some(outcome.ip);
+                self.summary.updated = outcome.updated;
+            }
+            self.summary.success = result.is_ok();
+            self.summary.elapsed_ms = start.elapsed().as_millis();
+            self.summary.print(self.options);
+        }
+
+        result
+    }
+
+    fn run_cycle(&mut self) -> Result<Outcome, Error> {
+        let options = self.options;
+
+        if !options.no_preflight {
+            timed(options, "Preflight", || self.preflight())?;
+        }
+
+        let zone_id = timed(options, "Zone resolution", || self.zone_id())?;
+        let zone_options = &options.cloudflare.zone_options;
+        self.summary.zone_source = Some(if zone_options.id.is_some() {
+            "flag"
+        } else if zone_options.name.is_some() {
+            "api"
+        } else {
+            "derived"
+        });
+
+        if let Some(suffix) = &options.record_suffix {
+            return self.run_for_suffix(&zone_id, suffix);
+        }
+
+        let current_record = timed(options, "Record fetch", || self.current_record(&zone_id))?;
+        if current_record.is_none() && !options.create {
+            return Err(Error::from(format!(
+                "Could not find A record for {}. Use --create to create it.\n{}",
+                options.dns_record(),
+                describe_zone_records(&options.cloudflare, &zone_id)
+            )));
+        }
+
+        let external_ip = self.target_ip()?;
+
+        let changed = timed(options, "Record update", || {
+            self.update_record(&zone_id, current_record, external_ip)
+        })?;
+
+        if !options.dry_run {
+            write_ip_file(options, external_ip)?;
+        }
+
+        Ok(Outcome {
+            ip: external_ip,
+            updated: changed && !options.dry_run,
+            needs_update: changed,
+        })
+    }
+
+    /// Updates every A record whose name ends with the suffix. All records are attempted even if
+    /// some of them fail.
+    fn run_for_suffix(&mut self, zone_id: &str, suffix: &str) -> Result<Outcome, Error> {
+        let options = self.options;
+
+        let records = timed(options, "Record fetch", || {
+            fetch_dns_records_with_suffix(&options.cloudflare, zone_id, suffix)
+        })?;
+        if records.is_empty() {
+            return Err(Error::from(format!(
+                "Could not find any A records ending with {}.\n{}",
+                suffix,
+                describe_zone_records(&options.cloudflare, zone_id)
+            )));
+        }
+        if let Some(max_records) = options.max_records {
+            if records.len() > max_records {
+                return Err(Error::from(format!(
+                    "{} A records end with {}, which is more than --max-records {}. Nothing was \
+                     updated.",
+                    records.len(),
+                    suffix,
+                    max_records
+                )));
+            }
+        }
+        if options.is_verbose() {
+            let names: Vec<&str> = records.iter().map(|record| record.name.as_str()).collect();
+            eprintln!("Records ending with {}: {}", suffix, names.join(", "));
+        }
+
+        let external_ip = self.target_ip()?;
+
+        let total = records.len();
+        let mut changed = false;
+        let mut failures = Vec::new();
+        let mut vetoed = Vec::new();
+        for record in records {
+            let name = record.name.clone();
+            eprintln!("{}:", name);
+            match timed(options, "Record update", || {
+                self.update_record(zone_id, Some(record), external_ip)
+            }) {
+                Ok(record_changed) => changed |= record_changed,
+                Err(err @ Error::Vetoed(_)) => {
+                    eprintln!("{} {}", paint_warning("Vetoed:"), err);
+                    vetoed.push(name);
+                }
+                Err(err) => {
+                    eprintln!("{} {}", paint_warning("Failed:"), err);
+                    failures.push(name);
+                }
+            }
+        }
+
+        if !failures.is_empty() {
+            return Err(Error::from(format!(
+                "Failed to update {} of {} records: {}",
+                failures.len(),
+                total,
+                failures.join(", ")
+            )));
+        }
+        if !vetoed.is_empty() {
+            return Err(Error::Vetoed(format!(
+                "--pre-update-command vetoed the update of {} of {} records: {}",
+                vetoed.len(),
+                total,
+                vetoed.join(", ")
+            )));
+        }
+
+        if !options.dry_run {
+            write_ip_file(options, external_ip)?;
+        }
+
+        Ok(Outcome {
+            ip: external_ip,
+            updated: changed && !options.dry_run,
+            needs_update: changed,
+        })
+    }
+
+    /// Checks that the record points to the external IP without changing anything, and returns
+    /// the IP. A record that is out of date results in `Error::RecordOutdated`.
+    pub fn check(&mut self) -> Result<Ipv4Addr, Error> {
+        let options = self.options;
+
+        let zone_id = timed(options, "Zone resolution", || self.zone_id())?;
+        let current_record = timed(options, "Record fetch", || self.current_record(&zone_id))?;
+        let external_ip = self.target_ip()?;
+
+        let record = match current_record {
+            Some(record) => record,
+            None => {
+                return Err(Error::RecordOutdated(format!(
+                    "{} does not exist, but should point to {}",
+                    options.dns_record(),
+                    external_ip
+                )))
+            }
+        };
+
+        let ip_is_correct =
+            matches!(record.content, DnsContent::A { content: ip } if ip == external_ip);
+        let wanted_proxied = options.wanted_proxied().unwrap_or(record.proxied);
+
+        if ip_is_correct && wanted_proxied == record.proxied {
+            Ok(external_ip)
+        } else {
+            Err(Error::RecordOutdated(format!(
+                "{} is {} (proxied: {}), but should be A {} (proxied: {})",
+                record.name,
+                format_dns_content(&record.content),
+                record.proxied,
+                external_ip,
+                wanted_proxied
+            )))
+        }
+    }
+
+    /// Checks that the API token is active and can update the record, to give a clear error
+    /// instead of a failed request halfway through the run.
+    pub fn preflight(&self) -> Result<(), Error> {
+        preflight(
+            &self.options.cloudflare,
+            self.options.is_verbose(),
+            self.options.dry_run,
+            &self.cloudflare,
+        )
+        .map_err(Error::from)
+    }
+
+    /// Returns the ID of the zone, looking it up using the API if only the name of the zone is
+    /// known. A successful lookup is remembered, so later calls do not need the API.
+    pub fn zone_id(&mut self) -> Result<String, Error> {
+        if let Some(zone_id) = &self.zone_id {
+            return Ok(zone_id.clone());
+        }
+
+        let zone_id = find_zone_id(
+            &self.options.cloudflare,
+            Some(self.options.dns_record()),
+            self.options.is_verbose(),
+            &self.cloudflare,
+        )?;
+        self.zone_id = Some(zone_id.clone());
+        Ok(zone_id)
+    }
+
+    /// Fetches the DNS record that should be updated, if it exists.
+    pub fn current_record(&self, zone_id: &str) -> Result<Option<DnsRecord>, Error> {
+        fetch_current_dns_record(&self.options.cloudflare, zone_id, self.options.dns_record())
+            .map_err(Error::from)
+    }
+
+    /// The IP that the record should point to, which is the "content" from --patch if it has
+    /// one, and the detected external IP otherwise.
+    fn target_ip(&mut self) -> Result<Ipv4Addr, Error> {
+        let options = self.options;
+        match options.patch.and_then(|patch| patch.content) {
+            Some(ip) => {
+                if options.is_verbose() {
+                    eprintln!("Using {} from --patch instead of detecting the IP", ip);
+                }
+                Ok(ip)
+            }
+            None => timed(options, "IP detection", || self.determine_external_ip()),
+        }
+    }
+
+    /// Asks the IP services for the external IP. Addresses in the shared address space used by
+    /// carrier-grade NAT are rejected unless `allow_private` is set.
+    ///
+    /// The IP is remembered in the state file, so that `use_cached_on_failure` can fall back to
+    /// it when none of the services answer in a later run. The state file also keeps track of how
+    /// often each service answers, and services that answer more often are asked first. Dry runs
+    /// and --diff-only do not write to the state file, so that their IPs never count towards
+    /// `confirm_count`.
+    pub fn determine_external_ip(&mut self) -> Result<Ipv4Addr, Error> {
+        let mut sources = self.sources.clone();
+        if self.options.state_file.is_some() {
+            let health = &self.state.service_health;
+            let score = |source: &Arc<dyn IpSource>| {
+                health
+                    .get(&source.name())
+                    .copied()
+                    .unwrap_or_default()
+                    .score()
+            };
+            // The sort is stable, so sources with the same score keep their configured order.
+            sources.sort_by(|a, b| score(b).partial_cmp(&score(a)).unwrap_or(Ordering::Equal));
+        }
+
+        let mut results = Vec::new();
+        let detected = determine_external_ip_with_results(self.options, &sources, &mut results);
+
+        let mut found_ips: Vec<Ipv4Addr> = results
+            .iter()
+            .filter_map(|(_, answer)| match answer {
+                Answer::Ip(ip) => Some(*ip),
+                _ => None,
+            })
+            .collect();
+        self.summary.services_queried = results.len();
+        self.summary.services_succeeded = found_ips.len();
+        found_ips.sort_unstable();
+        found_ips.dedup();
+        self.summary.distinct_ips = found_ips.len();
+
+        if let Some(path) = &self.options.state_file {
+            for (name, answer) in results {
+                let succeeded = match answer {
+                    Answer::Ip(_) => true,
+                    Answer::Failed => false,
+                    // Being rate limited says nothing about whether the service works.
+                    Answer::RateLimited => continue,
+                };
+                self.state
+                    .service_health
+                    .entry(name)
+                    .or_default()
+                    .record(succeeded);
+            }
+            if self.options.is_verbose() {
+                print_service_health(&sources, &self.state);
+            }
+            self.save_detection_state(path)?;
+        }
+
+        let external_ip = match detected {
+            Ok(ip) => ip,
+            Err(err) => return self.cached_ip(err),
+        };
+
+        if is_cgnat_address(external_ip) {
+            if self.options.allow_private {
+                eprintln!(
+                    "{} {} is a carrier-grade NAT address. Continuing because of --allow-private.",
+                    paint_warning("Warning:"),
+                    external_ip
+                );
+            } else {
+                return Err(Error::CgnatAddress(external_ip));
+            }
+        }
+
+        check_ip_origin(self.options, external_ip)?;
+
+        if let Some(path) = &self.options.state_file {
+            self.state.ip_streak = if self.state.last_ip == Some(external_ip) {
+                self.state.ip_streak.saturating_add(1)
+            } else {
+                1
+            };
+            self.state.last_ip = Some(external_ip);
+            self.state.last_ip_detected = Some(unix_timestamp());
+            self.save_detection_state(path)?;
+        }
+
+        Ok(external_ip)
+    }
+
+    /// Saves what IP detection learned to the state file, unless this run may not change
+    /// anything.
+    fn save_detection_state(&self, path: &Path) -> Result<(), String> {
+        if self.options.dry_run || self.options.diff_only {
+            return Ok(());
+        }
+        save_state(path, &self.state)
+    }
+
+    /// Returns the cached IP from the state file in place of the detection error, if
+    /// `use_cached_on_failure` allows it.
+    fn cached_ip(&self, detection_error: String) -> Result<Ipv4Addr, Error> {
+        if !self.options.use_cached_on_failure {
+            return Err(Error::from(detection_error));
+        }
+
+        match (self.state.last_ip, self.state.last_ip_detected) {
+            (Some(ip), Some(detected)) => match seconds_since(detected) {
+                Some(age) if age <= self.options.cached_ip_max_age.as_secs() => {
+                    eprintln!(
+                        "{} {} Using the cached IP {} from {} second(s) ago.",
+                        paint_warning("Warning:"),
+                        detection_error,
+                        ip,
+                        age
+                    );
+                    Ok(ip)
+                }
+                Some(age) => Err(Error::from(format!(
+                    "{} The cached IP {} is too old to use ({} second(s)).",
+                    detection_error, ip, age
+                ))),
+                // Without knowing how old the IP is, it is safest to treat it as too old.
+                None => Err(Error::from(format!(
+                    "{} The cached IP {} was detected in the future, so the clock must have been \
+                     set back since. Not using it.",
+                    detection_error, ip
+                ))),
+            },
+            _ => Err(Error::from(format!(
+                "{} There is no cached IP to use instead.",
+                detection_error
+            ))),
+        }
+    }
+
+    /// Makes the record point to the IP, creating it first if there is no current record.
+    /// Returns `true` if the record was written to, or would have been without `dry_run`.
+    pub fn update_record(
+        &mut self,
+        zone_id: &str,
+        current_record: Option<DnsRecord>,
+        external_ip: Ipv4Addr,
+    ) -> Result<bool, Error> {
+        let record_name = current_record
+            .as_ref()
+            .map_or(self.options.dns_record(), |record| record.name.as_str())
+            .to_string();
+
+        let proxy_options;
+        let options = if self.options.proxy_on_unhealthy {
+            proxy_options = proxy_by_health(self.options, current_record.as_ref(), external_ip);
+            &proxy_options
+        } else {
+            self.options
+        };
+
+        let changed = update_record_if_needed(
+            options,
+            &self.cloudflare,
+            zone_id,
+            &self.state,
+            current_record,
+            external_ip,
+        )?;
+
+        if changed && !self.options.dry_run {
+            if let Some(path) = &self.options.state_file {
+                self.state.last_update = Some(unix_timestamp());
+                save_state(path, &self.state)?;
+            }
+
+            if self.options.verify_after_update {
+                self.verify_update(zone_id, external_ip)?;
+            }
+
+            self.update_providers(&record_name, external_ip);
+        }
+
+        Ok(changed)
+    }
+
+    /// Passes the new IP on to the other DNS providers. Failures are only reported as warnings,
+    /// since Cloudflare is the primary provider and was already updated.
+    fn update_providers(&self, record_name: &str, external_ip: Ipv4Addr) {
+        for provider in &self.providers {
+            match provider.update_record(record_name, external_ip) {
+                Ok(()) => {
+                    if !self.options.is_quiet() {
+                        eprintln!(
+                            "{}",
+                            paint_success(format!(
+                                "Updated {} at {} to point to {}",
+                                record_name,
+                                provider.name(),
+                                external_ip
+                            ))
+                        );
+                    }
+                }
+                Err(err) => eprintln!(
+                    "{} Failed to update {} at {}: {}",
+                    paint_warning("Warning:"),
+                    record_name,
+                    provider.name(),
+                    err
+                ),
+            }
+        }
+    }
+
+    /// Reads the record back until it points to the IP, giving up after a few attempts.
+    fn verify_update(&self, zone_id: &str, external_ip: Ipv4Addr) -> Result<(), Error> {
+        for attempt in 1..=VERIFY_AFTER_UPDATE_ATTEMPTS {
+            thread::sleep(VERIFY_AFTER_UPDATE_DELAY);
+
+            let record = self.current_record(zone_id)?;
+            let is_visible = matches!(
+                record,
+                Some(DnsRecord {
+                    content: DnsContent::A { content: ip },
+                    ..
+                }) if ip == external_ip
+            );
+
+            if is_visible {
+                if self.options.is_verbose() {
+                    eprintln!("Read back the record and it points to {}", external_ip);
+                }
+                return Ok(());
+            }
+
+            if self.options.is_verbose() {
+                eprintln!(
+                    "Record does not point to {} yet ({} of {} attempts)",
+                    external_ip, attempt, VERIFY_AFTER_UPDATE_ATTEMPTS
+                );
+            }
+        }
+
+        Err(Error::UpdateNotVisible(external_ip))
+    }
+}
+
+/// Runs `--health-check` against the new IP. Returns `false` when the record should be left alone.
+fn passes_health_check(options: &Options, external_ip: Ipv4Addr) -> Result<bool, Error> {
+    let check = match &options.health_check {
+        Some(check) => check,
+        None => return Ok(true),
+    };
+
+    match run_health_check(options, check, external_ip) {
+        Ok(()) => {
+            if options.is_verbose() {
+                eprintln!("Health check of {} at {} passed", check, external_ip);
+            }
+            Ok(true)
+        }
+        Err(err) => match options.on_health_check_failure {
+            HealthCheckFailure::Skip => {
+                eprintln!(
+                    "{} Health check of {} at {} failed: {}. Leaving the record alone.",
+                    paint_warning("Warning:"),
+                    check,
+                    external_ip,
+                    err
+                );
+                Ok(false)
+            }
+            HealthCheckFailure::Fail => Err(Error::from(format!(
+                "Health check of {} at {} failed: {}",
+                check, external_ip, err
+            ))),
+        },
+    }
+}
+
+/// Runs `--health-check` for `--proxy-on-unhealthy`, and returns the options to update the record
+/// with: proxied when the check failed and DNS only when it passed.
+fn proxy_by_health(
+    options: &Options,
+    current_record: Option<&DnsRecord>,
+    external_ip: Ipv4Addr,
+) -> Options {
+    let check = options
+        .health_check
+        .as_ref()
+        .expect("Programmer error: --proxy-on-unhealthy requires --health-check");
+    let result = run_health_check(options, check, external_ip);
+    let proxied = result.is_err();
+
+    let record_name = current_record.map_or(options.dns_record(), |record| record.name.as_str());
+    // New records are DNS only unless told otherwise.
+    let transition = current_record.map_or(proxied, |record| record.proxied != proxied);
+    match &result {
+        Err(err) if transition => eprintln!(
+            "{} Health check of {} at {} failed: {}. Turning on the Cloudflare proxy for {}.",
+            paint_warning("Warning:"),
+            check,
+            external_ip,
+            err,
+            record_name
+        ),
+        Ok(()) if transition && !options.is_quiet() => eprintln!(
+            "Health check of {} at {} passed. Turning off the Cloudflare proxy for {}.",
+            check, external_ip, record_name
+        ),
+        Err(err) if options.is_verbose() => eprintln!(
+            "Health check of {} at {} failed: {}. {} stays proxied.",
+            check, external_ip, err, record_name
+        ),
+        Ok(()) if options.is_verbose() => eprintln!(
+            "Health check of {} at {} passed. {} stays DNS only.",
+            check, external_ip, record_name
+        ),
+        _ => {}
+    }
+
+    Options {
+        proxied: Some(proxied),
+        patch: options.patch.map(|patch| RecordPatch {
+            proxied: None,
+            ..patch
+        }),
+        // The check has already decided what to do, so it should not leave the record alone.
+        health_check: None,
+        ..options.clone()
+    }
+}
+
+/// Checks that the IP has been detected in enough runs in a row for --confirm-count. Returns
+/// `false` when the record should be left alone for now.
+fn is_confirmed(options: &Options, state: &State, external_ip: Ipv4Addr) -> bool {
+    let confirm_count = match options.confirm_count {
+        Some(confirm_count) => confirm_count,
+        None => return true,
+    };
+
+    let confirmed = state.ip_streak >= confirm_count;
+    if options.verbosity() >= Verbosity::Debug {
+        eprintln!(
+            "{} has been detected {} of --confirm-count {} time(s) in a row{}",
+            external_ip,
+            state.ip_streak.min(confirm_count),
+            confirm_count,
+            if confirmed {
+                ""
+            } else {
+                ". Leaving the record alone until it is confirmed."
+            }
+        );
+    }
+    confirmed
+}
+
+/// Returns `true` if the record was changed, or would have been without --dry-run.
+fn update_record_if_needed(
+    options: &Options,
+    cloudflare: &CloudflareClient,
+    zone_id: &str,
+    state: &State,
+    current_record: Option<DnsRecord>,
+    external_ip: Ipv4Addr,
+) -> Result<bool, Error> {
+    let current_record = match current_record {
+        Some(record) => record,
+        None => {
+            if let Some(expected) = options.expected_current_ip {
+                return Err(Error::UnexpectedRecord {
+                    expected,
+                    found: None,
+                });
+            }
+
+            let proxied = options.wanted_proxied().unwrap_or(false);
+
+            if !is_confirmed(options, state, external_ip)
+                || !passes_health_check(options, external_ip)?
+            {
+                return Ok(false);
+            }
+
+            if options.dry_run {
+                eprintln!(
+                    "Would create DNS record {} pointing to {} (TTL: {}, proxied: {})",
+                    options.dns_record(),
+                    external_ip,
+                    format_ttl(options.wanted_ttl().unwrap_or(1)),
+                    proxied
+                );
+            } else {
+                run_pre_update_command(options, options.dns_record(), "", external_ip)?;
+                let record = create_dns_record(
+                    cloudflare,
+                    zone_id,
+                    options.dns_record(),
+                    external_ip,
+                    options.wanted_ttl(),
+                    proxied,
+                )?;
+                if !options.is_quiet() {
+                    eprintln!(
+                        "{}",
+                        paint_success(format!(
+                            "Created DNS record pointing to {} (TTL: {}, proxied: {})",
+                            external_ip,
+                            format_ttl(record.ttl),
+                            record.proxied
+                        ))
+                    );
+                }
+                write_history(options, options.dns_record(), "-", external_ip);
+                send_notification(options, &record.name, &record.zone_name, "", external_ip);
+            }
+
+            return Ok(true);
+        }
+    };
+
+    let is_apex = current_record.name == current_record.zone_name;
+    if is_apex && options.is_verbose() {
+        match &current_record.content {
+            DnsContent::CNAME { content: target } => eprintln!(
+                "{} is the zone apex and is managed as a CNAME to {}, which Cloudflare flattens \
+                 into the addresses of the target",
+                current_record.name, target
+            ),
+            _ => eprintln!(
+                "{} is the zone apex and is managed as an A record",
+                current_record.name
+            ),
+        }
+    }
+
+    if let DnsContent::CNAME { content: target } = &current_record.content {
+        if is_apex && !options.force {
+            return Err(Error::from(format!(
+                "{name} is a CNAME to {target} at the zone apex. Cloudflare serves it using CNAME \
+                 flattening, so it resolves to the addresses of {target}. Writing an A record \
+                 would replace the CNAME. Pass --force if you really want to do that.",
+                name = current_record.name,
+                target = target
+            )));
+        }
+    }
+
+    let ip_is_correct =
+        matches!(current_record.content, DnsContent::A { content: ip } if ip == external_ip);
+    let proxied_is_correct = match options.wanted_proxied() {
+        Some(proxied) => proxied == current_record.proxied,
+        None => true,
+    };
+    let ttl_is_correct = match options.wanted_ttl() {
+        Some(ttl) => ttl == current_record.ttl,
+        None => true,
+    };
+
+    if !ip_is_correct {
+        if let Some(expected) = options.expected_current_ip {
+            if !matches!(current_record.content, DnsContent::A { content: ip } if ip == expected) {
+                return Err(Error::UnexpectedRecord {
+                    expected,
+                    found: Some(format_dns_content(&current_record.content)),
+                });
+            }
+        }
+    }
+
+    if ip_is_correct && proxied_is_correct && ttl_is_correct {
+        if !options.is_quiet() {
+            eprintln!(
+                "{}",
+                paint_success("Existing record is already correct. Exiting without changes.")
+            );
+        }
+        return Ok(false);
+    }
+
+    if ip_is_correct {
+        if let (Some(interval), Some(last_update)) =
+            (options.update_at_most_every, state.last_update)
+        {
+            match seconds_since(last_update) {
+                Some(elapsed) if elapsed < interval.as_secs() => {
+                    if !options.is_quiet() {
+                        eprintln!(
+                            "Record was last updated {} second(s) ago. Skipping update since \
+                             the IP is unchanged and --update-at-most-every has not passed yet.",
+                            elapsed
+                        );
+                    }
+                    return Ok(false);
+                }
+                Some(_) => {}
+                // Waiting until the clock catches up could take arbitrarily long, so count the
+                // interval as passed instead.
+                None => {
+                    if options.is_verbose() {
+                        eprintln!(
+                            "The last update is in the future, so the clock must have been set \
+                             back since. Treating --update-at-most-every as passed."
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    if options.is_verbose() {
+        if !ip_is_correct {
+            eprintln!(
+                "IP difference: DNS is set to {dns:?}, while current IP is {current}",
+                dns = current_record.content,
+                current = external_ip
+            );
+        }
+        if !proxied_is_correct {
+            eprintln!(
+                "Proxied difference: DNS record has proxied set to {}",
+                current_record.proxied
+            );
+        }
+        if !ttl_is_correct {
+            eprintln!(
+                "TTL difference: DNS record has a TTL of {}",
+                format_ttl(current_record.ttl)
+            );
+        }
+    }
+
+    if !ip_is_correct
+        && (!is_confirmed(options, state, external_ip)
+            || !passes_health_check(options, external_ip)?)
+    {
+        return Ok(false);
+    }
+
+    if options.dry_run {
+        eprintln!("Would update DNS record to point to {}", external_ip);
+        print_update_preview(
+            &current_record,
+            &update_params(options, &current_record, external_ip),
+        );
+        Ok(true)
+    } else {
+        let old_ip = match &current_record.content {
+            DnsContent::A { content } => content.to_string(),
+            content => format_dns_content(content),
+        };
+        run_pre_update_command(options, &current_record.name, &old_ip, external_ip)?;
+        let record = update_dns_record(options, cloudflare, zone_id, &current_record, external_ip)?;
+        // Unless --ttl is given the TTL is not sent, so show what Cloudflare actually ended up with
+        // to make it clear that it was kept.
+        if !options.is_quiet() {
+            eprintln!(
+                "{}",
+                paint_success(format!(
+                    "Updated DNS record to point to {} (TTL: {}{}, proxied: {})",
+                    external_ip,
+                    format_ttl(record.ttl),
+                    if record.ttl == current_record.ttl {
+                        ", unchanged"
+                    } else {
+                        ""
+                    },
+                    record.proxied
+                ))
+            );
+        }
+        write_history(
+            options,
+            &current_record.name,
+            &format_dns_content(&current_record.content),
+            external_ip,
+        );
+        send_notification(
+            options,
+            &current_record.name,
+            &current_record.zone_name,
+            &old_ip,
+            external_ip,
+        );
+        Ok(true)
+    }
+}
+
+/// Writes the IP to the file from `--write-ip-to`, if one is used.
+fn write_ip_file(options: &Options, ip: Ipv4Addr) -> Result<(), String> {
+    let path = match &options.write_ip_to {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+
+    let contents = match options.write_ip_format {
+        IpFileFormat::Bare => format!("{}\n", ip),
+        IpFileFormat::Json => format!(
+            "{}\n",
+            serde_json::json!({
+                "ip": ip.to_string(),
+                "record": options.dns_record(),
+                "time": Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            })
+        ),
+    };
+
+    write_atomically(path, &contents)
+        .map_err(|err| format!("Failed to write the IP to {}: {}", path.display(), err))
+}
+
+fn print_service_health(sources: &[Arc<dyn IpSource>], state: &State) {
+    eprintln!("IP service health:");
+    for source in sources {
+        let name = source.name();
+        match state.service_health.get(&name) {
+            Some(health) => eprintln!(
+                "  {} {:.0}% ({} of {})",
+                name,
+                health.score() * 100.0,
+                health.successes,
+                health.successes + health.failures
+            ),
+            None => eprintln!("  {} (no history)", name),
+        }
+    }
+}
+
+/// Runs one phase of the program, printing how long it took when in verbose mode.
+fn timed<T>(options: &Options, phase: &str, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+
+    if options.is_verbose() {
+        eprintln!("{} took {} ms", phase, start.elapsed().as_millis());
+    }
+
+    result
+}
+
+/// Reports the outcome of a run to a healthchecks.io style URL. Successful runs ping the URL
+/// itself and failed runs ping it with "/fail" added, both with a short description of the
+/// outcome as the body. A failed ping only results in a warning.
+pub fn send_healthcheck(options: &Options, url: &Url, result: &Result<Outcome, Error>) {
+    let (url, body) = match result {
+        Ok(outcome) => {
+            let body = if outcome.updated {
+                format!("Updated {} to {}", options.dns_record(), outcome.ip)
+            } else {
+                format!(
+                    "No changes needed for {} ({})",
+                    options.dns_record(),
+                    outcome.ip
+                )
+            };
+            (url.clone(), body)
+        }
+        Err(error) => {
+            // Failures are reported to the /fail endpoint below the ping URL, keeping its query.
+            let mut fail_url = url.clone();
+            if fail_url
+                .path_segments_mut()
+                .map(|mut segments| {
+                    segments.pop_if_empty().push("fail");
+                })
+                .is_err()
+            {
+                eprintln!(
+                    "{} Failed to report to the healthcheck URL: {} cannot have a /fail path",
+                    paint_warning("Warning:"),
+                    url.host_str().unwrap_or("it")
+                );
+                return;
+            }
+            (
+                fail_url,
+                format!("Exit code {}: {}", error.exit_code(), error),
+            )
+        }
+    };
+
+    let result = http_client(options).and_then(|client| {
+        client
+            .post(url)
+            .body(body)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| err.without_url().to_string())
+    });
+
+    match result {
+        Ok(_) => {
+            if options.is_verbose() {
+                eprintln!("Reported the outcome to the healthcheck URL");
+            }
+        }
+        Err(err) => eprintln!(
+            "{} Failed to report to the healthcheck URL: {}",
+            paint_warning("Warning:"),
+            err
+        ),
+    }
+}
+
+/// Sends a GET request to the heartbeat URL. A failure is only reported as a warning.
+pub fn send_heartbeat(options: &Options, url: &Url) {
+    let result = http_client(options).and_then(|client| {
+        client
+            .get(url.clone())
+            .send()
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| err.without_url().to_string())
+    });
+
+    // Only the host is shown, since heartbeat URLs contain the secret token.
+    match result {
+        Ok(_) => {
+            if options.is_verbose() {
+                eprintln!(
+                    "Sent heartbeat to {}",
+                    url.host_str().unwrap_or("unknown host")
+                );
+            }
+        }
+        Err(err) => eprintln!(
+            "{} Failed to send heartbeat: {}",
+            paint_warning("Warning:"),
+            err
+        ),
+    }
+}
@@ -1,447 +1,276 @@
-use clap::{Args, Parser};
-use cloudflare::endpoints::dns::{self, DnsContent};
-use cloudflare::endpoints::zone;
-use cloudflare::framework::auth::Credentials;
-use cloudflare::framework::response::{ApiErrors, ApiFailure};
-use cloudflare::framework::{Environment, HttpApiClientConfig};
-use cloudflare::{endpoints::dns::DnsRecord, framework::HttpApiClient as CloudflareClient};
+use clap::Parser;
+use cloudflare_dyndns::{
+    init_color, list_records, paint_error, paint_warning, print_history, print_version,
+    probe_services, push_metrics, saas_origin, send_healthcheck, send_heartbeat, verify_token,
+    CloudflareOptions, Command, DynDns, Error, Options, Outcome, TokenCommand, Verbosity,
+    ZoneOptions,
+};
 use dotenv::dotenv;
-use regex::Regex;
-use reqwest::blocking::{Client, ClientBuilder};
-use reqwest::Url;
-use std::collections::HashMap;
-use std::net::Ipv4Addr;
-use std::time::Duration;
-
-const IP_SERVICE_URLS: [&str; 7] = [
-    // HTTPS sources
-    "https://checkip.amazonaws.com/",
-    "https://httpbin.org/ip",
-    "https://icanhazip.com/",
-    "https://ipecho.net/plain",
-    "https://ipinfo.io/ip",
-    // HTTP sources
-    "http://checkip.dyndns.com/",
-    "http://whatismyip.akamai.com/",
-];
-const IPV4_MATCHER: &str = r"\b\d{1,3}(\.\d{1,3}){3}\b";
-
-#[derive(Parser, Debug)]
-#[command(
-    author,
-    about,
-    version,
-    next_line_help = true,
-    args_override_self = true
-)]
-struct Options {
-    /// Increase log output to show what the application is doing.
-    #[arg(long = "verbose", short = 'v')]
-    verbose: bool,
-
-    /// Don't actually update the DNS record and instead only exit with the IP that would be
-    /// written.
-    #[arg(long = "dry-run", short = 'n')]
-    dry_run: bool,
-
-    /// The Cloudflare API token.
-    #[arg(
-        long = "token",
-        short = 't',
-        env = "CLOUDFLARE_API_TOKEN",
-        value_name = "TOKEN",
-        help_heading = "Cloudflare"
-    )]
-    api_token: String,
-
-    #[command(flatten)]
-    zone_options: ZoneOptions,
-
-    /// The name of the DNS record to update ("example.com")
-    #[arg(env = "CLOUDFLARE_DNS_RECORD", value_name = "RECORD")]
-    dns_record: String,
-
-    /// Custom Cloudflare API base URL. Will use Cloudflare Production if not specified.
-    #[arg(
-        long = "cloudflare-api-url",
-        env = "CLOUDFLARE_API_URL",
-        value_name = "URL",
-        help_heading = "Cloudflare"
-    )]
-    base_url: Option<Url>,
-
-    /// Request timeout for IP services.
-    #[arg(
-        long = "ip-timeout",
-        value_name = "SECONDS",
-        default_value = "5",
-        help_heading = "IP"
-    )]
-    ip_timeout: u16,
-
-    /// Talk to all available IP services and check that an absolute majority of them have the same
-    /// answer before making any changes. Use this if you are extra paranoid and don't want a
-    /// hacked or buggy service to be able to give you the wrong IP back.
-    #[arg(long = "verify", help_heading = "IP")]
-    verify: bool,
-}
-
-#[derive(Args, Debug)]
-#[group(required = true, multiple = true)]
-struct ZoneOptions {
-    /// The name of the zone to update ("6d3cf337c06d898fc4743293fda5ea3a").
-    #[arg(
-        long = "zone-id",
-        env = "CLOUDFLARE_ZONE_ID",
-        value_name = "ID",
-        help_heading = "Cloudflare"
-    )]
-    id: Option<String>,
-
-    /// The name of the zone to update ("example.com"). If no Zone ID is set, then this name is
-    /// used to look up the Zone ID using the API.
-    #[arg(
-        long = "zone-name",
-        env = "CLOUDFLARE_ZONE_NAME",
-        value_name = "NAME",
-        help_heading = "Cloudflare"
-    )]
-    name: Option<String>,
-}
-
-impl Options {
-    fn cloudflare_credentials(&self) -> Credentials {
-        Credentials::UserAuthToken {
-            token: self.api_token.clone(),
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn main() -> ExitCode {
+    let env_file = match load_env_file() {
+        Ok(env_file) => env_file,
+        // This happens before colors are set up, so the error is printed without them.
+        Err(error) => return report_error(error),
+    };
+    let options = Options::parse();
+    init_color(&options);
+    report_env_file(&options, env_file);
+    if options.verbosity() >= Verbosity::Debug {
+        for resolve in &options.cloudflare.resolves {
+            eprintln!(
+                "Connecting to {} at {} (--resolve)",
+                resolve.host, resolve.ip
+            );
         }
     }
 
-    fn cloudflare_environment(&self) -> Environment {
-        match &self.base_url {
-            Some(url) => Environment::Custom(url.to_owned()),
-            None => Environment::Production,
-        }
-    }
-}
+    let result = run(&options);
 
-fn main() -> Result<(), String> {
-    dotenv().ok();
-    let options = Options::parse();
+    // Make sure all results are written before exiting, no matter how stdout is buffered.
+    let _ = io::stdout().flush();
 
-    if options.ip_timeout == 0 {
-        return Err(String::from(
-            "A timeout of 0 seconds would mean no request could ever work.",
-        ));
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => report_error(error),
     }
+}
 
-    let cloudflare = CloudflareClient::new(
-        options.cloudflare_credentials(),
-        HttpApiClientConfig::default(),
-        options.cloudflare_environment(),
-    )
-    .map_err(|err| format!("Failed to initialize Cloudflare API client: {}", err))?;
-
-    let zone_id = find_zone_id(&options, &cloudflare)?;
-
-    let current_record = fetch_current_dns_record(&cloudflare, &zone_id, &options.dns_record)?;
-    let external_ip = determine_external_ip(&options)?;
+fn report_error(error: Error) -> ExitCode {
+    // A veto is the command doing its job rather than something going wrong, and an outdated
+    // record is what --diff-only and --exit-code-on-change are asked to find.
+    let label = match error {
+        Error::Vetoed(_) => paint_warning("Vetoed:"),
+        Error::RecordOutdated(_) => paint_warning("Outdated:"),
+        _ => paint_error("Error:"),
+    };
+    eprintln!("{} {}", label, error);
+    ExitCode::from(error.exit_code())
+}
 
-    match current_record.content {
-        DnsContent::A { content: ip } if ip == external_ip => {
-            eprintln!("Existing record is already correct. Exiting without changes.");
-            Ok(())
+fn run(options: &Options) -> Result<(), Error> {
+    match &options.command {
+        Some(Command::History { limit }) => print_history(options, *limit).map_err(Error::from),
+        Some(Command::ListRecords {
+            cloudflare,
+            record_type,
+            name_contains,
+        }) => list_records(
+            options,
+            cloudflare,
+            record_type.as_deref(),
+            name_contains.as_deref(),
+        )
+        .map_err(Error::from),
+        Some(Command::ProbeServices { ip_services_file }) => {
+            let options = Options {
+                ip_services_file: ip_services_file.clone(),
+                ..options.clone()
+            };
+            probe_services(&options).map_err(Error::from)
         }
-        _ => {
-            if options.verbose {
-                eprintln!(
-                    "IP difference: DNS is set to {dns:?}, while current IP is {current}",
-                    dns = current_record.content,
-                    current = external_ip
-                );
-            }
-
-            if options.dry_run {
-                eprintln!("Would update DNS record to point to {}", external_ip);
-                Ok(())
+        Some(Command::Token {
+            command:
+                TokenCommand::Verify {
+                    api_token,
+                    base_url,
+                },
+        }) => {
+            let cloudflare = CloudflareOptions {
+                api_token: Some(api_token.clone()),
+                zone_options: ZoneOptions {
+                    id: None,
+                    name: None,
+                },
+                account_id: None,
+                base_url: base_url.clone(),
+                resolves: Vec::new(),
+            };
+            verify_token(options, &cloudflare).map_err(Error::from)
+        }
+        Some(Command::SaasOrigin {
+            cloudflare,
+            custom_hostname_id,
+        }) => run_saas_origin_update(options, cloudflare, custom_hostname_id),
+        Some(Command::Version) => print_version(options).map_err(Error::from),
+        None => {
+            sleep_before_start(options);
+            if options.diff_only {
+                run_check(options)
             } else {
-                update_dns_record(&cloudflare, &zone_id, current_record, external_ip)
+                run_update_cycle(options)
             }
         }
     }
 }
 
-fn find_zone_id(options: &Options, cloudflare: &CloudflareClient) -> Result<String, String> {
-    if let Some(id) = &options.zone_options.id {
-        return Ok(id.to_owned());
-    }
-
-    let name = options
-        .zone_options
-        .name
-        .as_ref()
-        .ok_or_else(|| "Neither Zone ID or Zone Name was specified".to_string())?;
-
-    if options.verbose {
-        eprint!("Resolving Zone ID… ");
-    }
-
-    let zones = cloudflare
-        .request(&zone::ListZones {
-            params: zone::ListZonesParams {
-                name: Some(name.to_owned()),
-                ..Default::default()
-            },
-        })
-        .map_err(|err| {
-            dbg!(&err);
-            format!(
-                "Failed to retreive zone ID: {}",
-                format_cloudflare_api_failure(err)
-            )
-        })?
-        .result;
-
-    let zone = zones
-        .into_iter()
-        .find(|zone| &zone.name == name)
-        .ok_or_else(|| {
-            format!(
-                "Failed to retrieve zone ID: No ones with name {} found",
-                name
-            )
-        })?;
-
-    if options.verbose {
-        eprintln!("OK. Found {}", zone.id);
-    }
-
-    Ok(zone.id)
+/// Where the environment variables were loaded from. The file is loaded before the options are
+/// parsed, since it can provide options of its own.
+enum EnvFile {
+    Loaded(PathBuf),
+    NotFound,
+    /// The .env file in the working directory could not be loaded, which has never been fatal.
+    Ignored(dotenv::Error),
 }
 
-fn fetch_current_dns_record(
-    cloudflare: &CloudflareClient,
-    zone_id: &str,
-    record_name: &str,
-) -> Result<DnsRecord, String> {
-    let request = dns::ListDnsRecords {
-        zone_identifier: zone_id,
-        params: dns::ListDnsRecordsParams {
-            name: Some(record_name.to_owned()),
-            ..Default::default()
+/// Loads the file from --env-file or `DYNDNS_ENV_FILE`, which must work, or else the .env file
+/// in the working directory if there is one.
+fn load_env_file() -> Result<EnvFile, Error> {
+    match env_file_path() {
+        Some(path) => match dotenv::from_path(&path) {
+            Ok(()) => Ok(EnvFile::Loaded(path)),
+            Err(err) => Err(Error::from(format!(
+                "Failed to load environment variables from {}: {}",
+                path.display(),
+                err
+            ))),
         },
-    };
-
-    let records = cloudflare
-        .request(&request)
-        .map_err(|err| {
-            format!(
-                "Failed to list DNS records for zone {}: {}",
-                zone_id,
-                format_cloudflare_api_failure(err)
-            )
-        })?
-        .result;
-
-    records
-        .into_iter()
-        .find(|record| record.name == record_name)
-        .ok_or_else(|| format!("Could not find A record for {}", record_name))
-}
-
-fn update_dns_record(
-    cloudflare: &CloudflareClient,
-    zone_id: &str,
-    current_record: DnsRecord,
-    new_ip: Ipv4Addr,
-) -> Result<(), String> {
-    let request = dns::UpdateDnsRecord {
-        zone_identifier: zone_id,
-        identifier: &current_record.id,
-        params: dns::UpdateDnsRecordParams {
-            name: &current_record.name,
-            content: DnsContent::A { content: new_ip },
-            ttl: None,
-            proxied: None,
+        None => match dotenv() {
+            Ok(path) => Ok(EnvFile::Loaded(path)),
+            Err(err) if err.not_found() => Ok(EnvFile::NotFound),
+            Err(err) => Ok(EnvFile::Ignored(err)),
         },
-    };
-
-    cloudflare
-        .request(&request)
-        .map_err(|err| {
-            format!(
-                "Failed to update DNS record: {}",
-                format_cloudflare_api_failure(err)
-            )
-        })
-        .map(|_| ())
-}
-
-fn http_client(options: &Options) -> Result<Client, String> {
-    ClientBuilder::new()
-        .timeout(Duration::from_secs(options.ip_timeout.into()))
-        .build()
-        .map_err(|error| format!("Failed to construct HTTP client: {}", error))
-}
-
-fn determine_external_ip(options: &Options) -> Result<Ipv4Addr, String> {
-    if options.verify {
-        determine_external_ip_with_verification(options)
-    } else {
-        determine_external_ip_without_verification(options)
     }
 }
 
-fn parse_ip(string: &str) -> Result<Ipv4Addr, String> {
-    string
-        .parse()
-        .map_err(|err| format!("Failed to parse IP address {}: {}", string, err))
-}
-
-fn determine_external_ip_without_verification(options: &Options) -> Result<Ipv4Addr, String> {
-    let matcher: Regex = IPV4_MATCHER
-        .parse()
-        .expect("Programmer error: Invalid regexp");
-    let client = http_client(options)?;
-
-    if !options.verbose {
-        eprint!("Retreiving external IP… ");
-    }
-
-    for url in IP_SERVICE_URLS.iter() {
-        if options.verbose {
-            eprint!("{} -> ", url);
+/// Finds --env-file by hand, since the options can only be parsed once the file is loaded.
+fn env_file_path() -> Option<PathBuf> {
+    let mut args = env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        } else if arg == "--env-file" {
+            return args.next().map(PathBuf::from);
+        } else if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--env-file=")) {
+            return Some(PathBuf::from(path));
         }
+    }
 
-        let found_ip = client
-            .get(*url)
-            .send()
-            .and_then(|result| result.text())
-            .map(|body| extract_ip_from_body(&body, &matcher));
+    env::var_os("DYNDNS_ENV_FILE").map(PathBuf::from)
+}
 
-        match &found_ip {
-            Ok(Some(ip)) => {
-                eprintln!("{}", ip);
-                return parse_ip(ip);
+fn report_env_file(options: &Options, env_file: EnvFile) {
+    match env_file {
+        EnvFile::Loaded(path) => {
+            if options.verbosity() >= Verbosity::Debug {
+                eprintln!("Loaded environment variables from {}", path.display());
             }
-            Ok(None) => {
-                if options.verbose {
-                    eprintln!("Failed. No IP found in response.")
-                }
-            }
-            Err(err) => {
-                if options.verbose {
-                    eprintln!("Failed. {}", err)
-                }
+        }
+        EnvFile::NotFound => {
+            if options.verbosity() >= Verbosity::Debug {
+                eprintln!("No .env file found, using the environment as is");
             }
         }
+        EnvFile::Ignored(err) => eprintln!(
+            "{} Ignoring the .env file: {}",
+            paint_warning("Warning:"),
+            err
+        ),
     }
-
-    Err(format!(
-        "None of the {} service(s) replied successfully.",
-        IP_SERVICE_URLS.len()
-    ))
 }
 
-fn determine_external_ip_with_verification(options: &Options) -> Result<Ipv4Addr, String> {
-    let matcher: Regex = IPV4_MATCHER
-        .parse()
-        .expect("Programmer error: Invalid regexp");
-    let client = http_client(options)?;
-
-    let mut votes: HashMap<String, u16> = HashMap::new();
+fn sleep_before_start(options: &Options) {
+    let max_seconds = match options.start_delay_max {
+        Some(seconds) if seconds > 0 => seconds,
+        _ => return,
+    };
 
-    let longest_url_length = IP_SERVICE_URLS
-        .iter()
-        .map(|url| url.len())
-        .max()
-        .unwrap_or(10);
+    // The hasher is seeded with random keys for every process, which is random enough to spread
+    // out the start times without pulling in a dependency.
+    let random = RandomState::new().build_hasher().finish();
+    let delay = Duration::from_millis(random % max_seconds.saturating_mul(1000).saturating_add(1));
 
-    if !options.verbose {
-        eprint!("Retreiving and validating external IP… ");
+    if options.is_verbose() {
+        eprintln!("Sleeping for {} ms before starting…", delay.as_millis());
     }
+    thread::sleep(delay);
+}
 
-    for url in IP_SERVICE_URLS.iter() {
-        if options.verbose {
-            eprint!("{0:>1$} -> ", url, longest_url_length);
-        }
+fn run_update_cycle(options: &Options) -> Result<(), Error> {
+    let start = Instant::now();
+    let result = DynDns::new(options).and_then(|mut dyndns| dyndns.run());
 
-        let found_ip = client
-            .get(*url)
-            .send()
-            .and_then(|result| result.text())
-            .map(|body| extract_ip_from_body(&body, &matcher));
+    if let Ok(outcome) = &result {
+        // Diagnostics go to stderr, so the IP is the only thing printed on stdout and can be used
+        // in scripts.
+        println!("{}", outcome.ip);
 
-        if options.verbose {
-            match &found_ip {
-                Ok(Some(ip)) => eprintln!("{}", ip),
-                Ok(None) => eprintln!("Failed. No IP found in response."),
-                Err(err) => eprintln!("Failed. {}", err),
-            }
+        if let Some(url) = &options.heartbeat_url {
+            send_heartbeat(options, url);
         }
+    }
 
-        if let Ok(Some(ip)) = found_ip {
-            *votes.entry(ip).or_insert(0) += 1;
-        }
+    if let Some(url) = &options.healthcheck_url {
+        send_healthcheck(options, url, &result);
+    }
+    if let Some(url) = &options.pushgateway_url {
+        push_metrics(options, url, &result, start.elapsed());
     }
 
-    match votes.len() {
-        0 => Err("Error: All sources failed. Aborting".to_string()),
-        1 => {
-            let ip = votes.keys().next().unwrap();
-            if options.verbose {
-                eprintln!("All services agree on {}", ip);
-            } else {
-                eprintln!("Done");
-            }
-            parse_ip(ip)
+    if let Some(seconds) = options.sleep_after {
+        if options.is_verbose() {
+            eprintln!("Sleeping for {} second(s) before exiting…", seconds);
         }
-        _ => {
-            eprintln!("Warning: Some services disagree on IP!");
-            let total_votes: u16 = votes.values().copied().sum();
-            let top_vote = votes.iter().max_by_key(|(_ip, tally)| *tally).unwrap();
-            // If the top vote got more than 2/3rds of the votes, it's in an absolute majority.
-            if *top_vote.1 >= (total_votes * 2 / 3) {
-                eprintln!(
-                    "IP {ip} has absolute majority of the votes ({tally} of {total})",
-                    ip = top_vote.0,
-                    tally = top_vote.1,
-                    total = votes.len()
-                );
-                parse_ip(top_vote.0)
-            } else {
-                eprintln!("No IP has absolute majority:");
-                for (ip, tally) in votes.iter() {
-                    eprintln!("  {}: {}", ip, tally);
-                }
-                eprintln!("Aborting.");
-                Err("Could not determine IP".to_string())
-            }
+        thread::sleep(Duration::from_secs(seconds));
+    }
+
+    match result {
+        Ok(outcome) if options.exit_code_on_change && outcome.needs_update => {
+            Err(Error::RecordOutdated(String::from(
+                "The record would be changed without --dry-run",
+            )))
         }
+        result => result.map(|_| ()),
     }
 }
 
-fn extract_ip_from_body(body: &str, matcher: &Regex) -> Option<String> {
-    matcher
-        .captures(body)
-        .map(|captures| captures[0].to_string())
+/// Looks up the origin of the custom hostname and runs a normal update cycle for its record.
+fn run_saas_origin_update(
+    options: &Options,
+    cloudflare: &CloudflareOptions,
+    custom_hostname_id: &str,
+) -> Result<(), Error> {
+    let origin = saas_origin(options, cloudflare, custom_hostname_id)?;
+    let options = Options {
+        command: None,
+        cloudflare: cloudflare.clone(),
+        dns_record: Some(origin),
+        ..options.clone()
+    };
+
+    run_update_cycle(&options)
 }
 
-fn format_cloudflare_api_failure(failure: ApiFailure) -> String {
-    match failure {
-        ApiFailure::Error(status, errors) => format!(
-            "Status code {status}:\n  {errors}",
-            status = status,
-            errors = format_cloudflare_errors(errors),
-        ),
-        ApiFailure::Invalid(err) => err.to_string(),
+fn run_check(options: &Options) -> Result<(), Error> {
+    let start = Instant::now();
+    let result = DynDns::new(options)
+        .and_then(|mut dyndns| dyndns.check())
+        .map(|ip| Outcome {
+            ip,
+            updated: false,
+            needs_update: false,
+        });
+
+    if let Some(url) = &options.healthcheck_url {
+        send_healthcheck(options, url, &result);
+    }
+    if let Some(url) = &options.pushgateway_url {
+        push_metrics(options, url, &result, start.elapsed());
     }
-}
 
-fn format_cloudflare_errors(errors: ApiErrors) -> String {
-    errors
-        .errors
-        .iter()
-        .map(|error| format!("{}: {}", error.code, error.message))
-        .collect::<Vec<String>>()
-        .join("\n  ")
+    // Like in a normal run, only the IP goes to stdout.
+    let outcome = result?;
+    if options.is_verbose() {
+        eprintln!("OK: {} points to {}", options.dns_record(), outcome.ip);
+    }
+    println!("{}", outcome.ip);
+    Ok(())
 }
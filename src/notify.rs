@@ -0,0 +1,122 @@
+//! Notifications that are sent when the record changes.
+
+use crate::color::paint_warning;
+use crate::ip::http_client;
+use crate::options::Options;
+use chrono::{SecondsFormat, Utc};
+use regex::Regex;
+use std::net::Ipv4Addr;
+
+/// The placeholders that can be used in `--notify-template`.
+const PLACEHOLDERS: [&str; 5] = ["record", "old_ip", "new_ip", "zone", "time"];
+const PLACEHOLDER_MATCHER: &str = r"\{([A-Za-z_]+)\}";
+
+/// Checks that the template only uses known placeholders. Other braces, like the ones in a JSON
+/// template, are left alone.
+pub(crate) fn parse_template(template: &str) -> Result<String, String> {
+    let matcher: Regex = PLACEHOLDER_MATCHER
+        .parse()
+        .expect("Programmer error: Invalid regexp");
+
+    for captures in matcher.captures_iter(template) {
+        if !PLACEHOLDERS.contains(&&captures[1]) {
+            return Err(format!(
+                "Unknown placeholder {}. Use one of {{{}}}.",
+                &captures[0],
+                PLACEHOLDERS.join("}, {")
+            ));
+        }
+    }
+
+    Ok(template.to_string())
+}
+
+/// Sends a POST request to each of the notification URLs. Failures are reported as warnings for
+/// each URL on its own, since the update itself already went through.
+pub(crate) fn send_notification(
+    options: &Options,
+    record: &str,
+    zone: &str,
+    old_value: &str,
+    new_ip: Ipv4Addr,
+) {
+    if options.notify_urls.is_empty() {
+        return;
+    }
+
+    let time = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+    let new_ip = new_ip.to_string();
+    let values = [
+        ("record", record),
+        ("old_ip", old_value),
+        ("new_ip", new_ip.as_str()),
+        ("zone", zone),
+        ("time", time.as_str()),
+    ];
+
+    let body = match &options.notify_template {
+        Some(template) => render_template(template, &values),
+        None => serde_json::Value::Object(
+            values
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string().into()))
+                .collect(),
+        )
+        .to_string(),
+    };
+
+    let client = match http_client(options) {
+        Ok(client) => client,
+        Err(err) => {
+            eprintln!(
+                "{} Failed to send notifications: {}",
+                paint_warning("Warning:"),
+                err
+            );
+            return;
+        }
+    };
+
+    for url in &options.notify_urls {
+        let result = client
+            .post(url.clone())
+            .header("Content-Type", options.notify_content_type.as_str())
+            .body(body.clone())
+            .send()
+            .and_then(|response| response.error_for_status());
+
+        // Only the host is shown, since webhook URLs often contain credentials.
+        match result {
+            Ok(_) => {
+                if options.is_verbose() {
+                    eprintln!(
+                        "Sent notification to {}",
+                        url.host_str().unwrap_or("unknown host")
+                    );
+                }
+            }
+            Err(err) => eprintln!(
+                "{} Failed to send notification to {}: {}",
+                paint_warning("Warning:"),
+                url.host_str().unwrap_or("unknown host"),
+                err.without_url()
+            ),
+        }
+    }
+}
+
+fn render_template(template: &str, values: &[(&str, &str)]) -> String {
+    let matcher: Regex = PLACEHOLDER_MATCHER
+        .parse()
+        .expect("Programmer error: Invalid regexp");
+
+    matcher
+        .replace_all(template, |captures: &regex::Captures| {
+            values
+                .iter()
+                .find(|(name, _)| *name == &captures[1])
+                .map(|(_, value)| value.to_string())
+                .expect("Programmer error: Template was not validated")
+        })
+        .into_owned()
+}
@@ -0,0 +1,1061 @@
+//! The command line options, which also configure the library.
+
+use crate::health_check::{parse_health_check, HealthCheck};
+use crate::ip::{parse_ipv4_range, Ipv4Range};
+use crate::notify::parse_template;
+use crate::provider::parse_url_template;
+use crate::source::parse_ip_regex;
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use cloudflare::framework::auth::Credentials;
+use cloudflare::framework::{Environment, HttpApiClient as CloudflareClient, HttpApiClientConfig};
+use regex::Regex;
+use reqwest::blocking::ClientBuilder;
+use reqwest::Url;
+use serde::Deserialize;
+use std::fs;
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// The lowest TTL that Cloudflare accepts, which is only available on Enterprise plans. Other
+/// plans need at least 60 seconds.
+const MIN_TTL: u64 = 30;
+const MAX_TTL: u64 = 24 * 60 * 60;
+
+#[derive(Parser, Clone, Debug)]
+#[command(
+    author,
+    about,
+    version,
+    next_line_help = true,
+    args_override_self = true,
+    subcommand_negates_reqs = true,
+    args_conflicts_with_subcommands = true
+)]
+pub struct Options {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Increase log output to show what the application is doing. Give it twice to also show the
+    /// replies from the IP services, and three times to show them in full.
+    #[arg(long = "verbose", short = 'v', action = ArgAction::Count, global = true)]
+    pub verbose: u8,
+
+    /// Decrease log output, so that only warnings, errors and the results are printed. Cancels
+    /// out one --verbose.
+    #[arg(long = "quiet", short = 'q', action = ArgAction::Count, global = true)]
+    pub quiet: u8,
+
+    /// Load environment variables from this file instead of from .env in the working directory.
+    /// It is an error if the file cannot be loaded.
+    #[arg(
+        long = "env-file",
+        env = "DYNDNS_ENV_FILE",
+        value_name = "PATH",
+        global = true
+    )]
+    pub env_file: Option<PathBuf>,
+
+    /// Print a short recap of the run on stderr at the end, which is always done with --verbose.
+    /// With --output json, the recap is a single line of JSON.
+    #[arg(long = "summary")]
+    pub summary: bool,
+
+    /// The format of the results printed on stdout.
+    #[arg(
+        long = "output",
+        value_enum,
+        default_value_t = OutputFormat::Text,
+        value_name = "FORMAT",
+        global = true
+    )]
+    pub output: OutputFormat,
+
+    /// Don't color the messages on stderr. They are never colored when stderr is not a terminal,
+    /// when the NO_COLOR environment variable is set, or with --output json.
+    #[arg(long = "no-color", global = true)]
+    pub no_color: bool,
+
+    /// Don't actually update the DNS record and instead only exit with the IP that would be
+    /// written. Everything else, like looking up the zone and the record, is done just like in a
+    /// real run, and a token that may not write DNS records results in a warning.
+    #[arg(long = "dry-run", short = 'n', global = true)]
+    pub dry_run: bool,
+
+    /// Only check if the record points to the current IP, without changing anything. Exits with
+    /// code 5 when the record is out of date, which makes this usable as a monitoring check.
+    #[arg(long = "diff-only", conflicts_with_all = ["dry_run", "create"])]
+    pub diff_only: bool,
+
+    /// With --dry-run, exit with code 5 when the record would be changed, just like --diff-only
+    /// does when the record is out of date. This makes a dry run usable as a drift check.
+    #[arg(long = "exit-code-on-change", requires = "dry_run")]
+    pub exit_code_on_change: bool,
+
+    /// After a single update cycle, sleep for this long before exiting. Useful when running in a
+    /// container with a restart policy, so that restarts happen at a reasonable cadence. The exit
+    /// status still reflects the outcome of the update.
+    #[arg(long = "sleep-after", value_name = "SECONDS")]
+    pub sleep_after: Option<u64>,
+
+    /// Before doing anything, sleep for a random time of up to this many seconds. Use this to
+    /// spread out the requests when many hosts run on the same schedule.
+    #[arg(long = "start-delay-max", value_name = "SECONDS")]
+    pub start_delay_max: Option<u64>,
+
+    #[command(flatten)]
+    pub cloudflare: CloudflareOptions,
+
+    /// Create the DNS record if it does not exist yet.
+    ///
+    /// New records are DNS only (not proxied) unless --proxied is given, since a dynamic DNS
+    /// record is usually meant to resolve directly to your own IP.
+    #[arg(long = "create", help_heading = "Cloudflare")]
+    pub create: bool,
+
+    /// Overwrite the record even when it looks like a mistake to do so, such as when the record
+    /// is a flattened CNAME at the zone apex.
+    #[arg(long = "force", help_heading = "Cloudflare")]
+    pub force: bool,
+
+    /// Skip checking that the API token is active and has the needed permissions before
+    /// updating. This saves an API call on every run.
+    #[arg(long = "no-preflight", help_heading = "Cloudflare")]
+    pub no_preflight: bool,
+
+    /// After writing the record, read it back to check that Cloudflare returns the new IP. This
+    /// is retried a few times, since a change can take a moment to show up.
+    #[arg(long = "verify-after-update", help_heading = "Cloudflare")]
+    pub verify_after_update: bool,
+
+    /// Whether the record should be proxied through Cloudflare, as "--proxied" or
+    /// "--proxied=false". When left out, existing records keep their current setting and new
+    /// records are DNS only.
+    #[arg(
+        long = "proxied",
+        value_name = "BOOL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        help_heading = "Cloudflare"
+    )]
+    pub proxied: Option<bool>,
+
+    /// Also keep the record in sync at another DNS provider, by sending a GET request to this URL
+    /// whenever the Cloudflare record is updated. The placeholders {record} and {ip} are
+    /// replaced with their values, and credentials can be put in the URL
+    /// ("https://user:password@…"). Can be given more than once.
+    #[arg(
+        long = "also-update-url",
+        value_name = "URL",
+        value_parser = parse_url_template
+    )]
+    pub also_update_urls: Vec<String>,
+
+    /// The TTL of the record, like "5m" or "1h", or "auto" to let Cloudflare decide. When left
+    /// out, existing records keep their current TTL and new records use "auto".
+    #[arg(
+        long = "ttl",
+        value_name = "DURATION",
+        value_parser = parse_ttl,
+        help_heading = "Cloudflare"
+    )]
+    pub ttl: Option<u32>,
+
+    /// Only compare and change the IP of the record. Differences in TTL or proxied setting never
+    /// cause an update, and an update writes back their current values. Use this when those
+    /// settings are managed somewhere else. The "ttl" and "proxied" of --patch are ignored.
+    #[arg(
+        long = "compare-ip-only",
+        conflicts_with_all = ["ttl", "proxied", "proxy_on_unhealthy"],
+        help_heading = "Cloudflare"
+    )]
+    pub compare_ip_only: bool,
+
+    /// Read overrides for the update as a JSON object from this file, or from stdin with "-".
+    /// The object may set "content" (the IP to write instead of the detected one), "ttl" (in
+    /// seconds, 1 for automatic) and "proxied". They take precedence over --ttl and --proxied.
+    /// Other keys are rejected. Record comments cannot be set, since the Cloudflare library that
+    /// is used does not support them.
+    #[arg(
+        long = "patch",
+        value_name = "PATH",
+        value_parser = parse_patch,
+        help_heading = "Cloudflare"
+    )]
+    pub patch: Option<RecordPatch>,
+
+    /// Only update the record if it currently points to this IP. If it has any other value,
+    /// something else might have changed it and the update is aborted with exit code 6. Nothing
+    /// happens if the record already points to the detected IP.
+    #[arg(
+        long = "expected-current-ip",
+        value_name = "IP",
+        help_heading = "Cloudflare"
+    )]
+    pub expected_current_ip: Option<Ipv4Addr>,
+
+    /// The name of the DNS record to update ("example.com"). Defaults to the zone name, which
+    /// updates the record at the apex of the zone.
+    #[arg(
+        env = "CLOUDFLARE_DNS_RECORD",
+        value_name = "RECORD",
+        value_parser = parse_domain_name,
+        required_unless_present_any = ["name", "record_suffix"]
+    )]
+    pub dns_record: Option<String>,
+
+    /// Instead of a single record, update every A record in the zone whose name ends with this
+    /// suffix (".home.example.com").
+    #[arg(
+        long = "record-suffix",
+        value_name = "SUFFIX",
+        value_parser = parse_record_suffix,
+        conflicts_with_all = ["dns_record", "diff_only", "create"],
+        help_heading = "Cloudflare"
+    )]
+    pub record_suffix: Option<String>,
+
+    /// Refuse to update anything if --record-suffix matches more records than this.
+    #[arg(
+        long = "max-records",
+        value_name = "COUNT",
+        requires = "record_suffix",
+        help_heading = "Cloudflare"
+    )]
+    pub max_records: Option<usize>,
+
+    /// Request timeout for IP services.
+    #[arg(
+        long = "ip-timeout",
+        value_name = "SECONDS",
+        default_value = "5",
+        help_heading = "IP"
+    )]
+    pub ip_timeout: u16,
+
+    /// Send requests to the IP services from this local address. Use this on hosts with multiple
+    /// uplinks to detect the external IP of a specific one.
+    #[arg(long = "source-addr", value_name = "IP", help_heading = "IP")]
+    pub source_addr: Option<IpAddr>,
+
+    /// Read the IP services to use from this file instead of using the built-in list. The file
+    /// should contain one URL per line. Blank lines and lines starting with "#" are ignored.
+    #[arg(long = "ip-services-file", value_name = "PATH", help_heading = "IP")]
+    pub ip_services_file: Option<PathBuf>,
+
+    /// Find the IP in the replies from the IP services with this regular expression instead of
+    /// the built-in one. The whole match must be the IP. Use this for services that reply in a
+    /// format that the built-in expression cannot handle.
+    #[arg(
+        long = "ip-regex",
+        value_name = "PATTERN",
+        value_parser = parse_ip_regex,
+        help_heading = "IP"
+    )]
+    pub ip_regex: Option<Regex>,
+
+    /// Ask the router for its external IP using UPnP instead of asking the IP services. When
+    /// combined with --verify, the router gets a vote alongside the IP services.
+    #[arg(long = "upnp", help_heading = "IP")]
+    pub upnp: bool,
+
+    /// Never accept this IP from an IP service, for example when a transparent proxy on the
+    /// network makes some services report its own address. The next service is asked instead,
+    /// and with --verify the vote is ignored. Can be given more than once.
+    #[arg(long = "exclude-ip", value_name = "IP", help_heading = "IP")]
+    pub exclude_ips: Vec<Ipv4Addr>,
+
+    /// Like --exclude-ip, but for a whole range of addresses in CIDR notation
+    /// ("192.0.2.0/24"). Can be given more than once.
+    #[arg(
+        long = "exclude-range",
+        value_name = "CIDR",
+        value_parser = parse_ipv4_range,
+        help_heading = "IP"
+    )]
+    pub exclude_ranges: Vec<Ipv4Range>,
+
+    /// Check with ipinfo.io that the detected IP belongs to this AS number, such as the one of
+    /// your ISP. A mismatch only results in a warning unless --strict-geo is given.
+    #[arg(long = "expect-asn", value_name = "NUMBER", value_parser = parse_asn, help_heading = "IP")]
+    pub expect_asn: Option<u32>,
+
+    /// Check with ipinfo.io that the detected IP is in this country, given as a two-letter code
+    /// ("SE"). A mismatch only results in a warning unless --strict-geo is given.
+    #[arg(long = "expect-country", value_name = "CODE", help_heading = "IP")]
+    pub expect_country: Option<String>,
+
+    /// Abort when the IP does not match --expect-asn or --expect-country, or when ipinfo.io
+    /// cannot be asked.
+    #[arg(long = "strict-geo", help_heading = "IP")]
+    pub strict_geo: bool,
+
+    /// The ipinfo.io API to use for --expect-asn and --expect-country.
+    #[arg(
+        long = "ipinfo-url",
+        value_name = "URL",
+        default_value = "https://ipinfo.io/",
+        help_heading = "IP"
+    )]
+    pub ipinfo_url: Url,
+
+    /// Accept a detected IP in the shared address space used by carrier-grade NAT
+    /// (100.64.0.0/10). Such an address belongs to your ISP rather than to you, so it is rejected
+    /// by default.
+    #[arg(long = "allow-private", help_heading = "IP")]
+    pub allow_private: bool,
+
+    /// Append a line to this file every time the DNS record is updated. The file is rotated once
+    /// it grows beyond 1 MiB, keeping a single older file with a ".1" suffix.
+    #[arg(
+        long = "history-file",
+        value_name = "PATH",
+        global = true,
+        help_heading = "Monitoring"
+    )]
+    pub history_file: Option<PathBuf>,
+
+    /// After a successful run, write the IP to this file so that other programs can read it. The
+    /// file is replaced atomically. Nothing is written with --dry-run.
+    #[arg(long = "write-ip-to", value_name = "PATH")]
+    pub write_ip_to: Option<PathBuf>,
+
+    /// The format of the file written by --write-ip-to. "bare" is just the IP, while "json" also
+    /// includes the record and the time.
+    #[arg(
+        long = "write-ip-format",
+        value_enum,
+        default_value_t = IpFileFormat::Bare,
+        value_name = "FORMAT",
+        requires = "write_ip_to"
+    )]
+    pub write_ip_format: IpFileFormat,
+
+    /// File to keep state in between runs. It is created if it does not exist. It also keeps
+    /// track of how often each IP service answers, and the most reliable services are asked
+    /// first.
+    #[arg(long = "state-file", value_name = "PATH")]
+    pub state_file: Option<PathBuf>,
+
+    /// Only update the record this often when the IP has not changed, for example when just the
+    /// proxied setting differs. A changed IP is always written right away. Accepts durations like
+    /// "90", "30s", "15m", "12h" or "1d". Requires --state-file.
+    #[arg(
+        long = "update-at-most-every",
+        value_name = "DURATION",
+        value_parser = parse_duration,
+        requires = "state_file"
+    )]
+    pub update_at_most_every: Option<Duration>,
+
+    /// Only point the record at a new IP once it has been detected in this many runs in a row,
+    /// so that a fluke IP from a single run is never written. Any other IP starts the count
+    /// over. Requires --state-file.
+    #[arg(
+        long = "confirm-count",
+        value_name = "COUNT",
+        value_parser = clap::value_parser!(u32).range(1..),
+        requires = "state_file"
+    )]
+    pub confirm_count: Option<u32>,
+
+    /// Only point the record at a new IP when a service answers there. "tcp:PORT" connects to
+    /// the port on the new IP, and "http:URL" sends a GET request to the URL with its host
+    /// resolved to the new IP. This avoids pointing the record at a connection that is not fully
+    /// up yet.
+    #[arg(
+        long = "health-check",
+        value_name = "CHECK",
+        value_parser = parse_health_check
+    )]
+    pub health_check: Option<HealthCheck>,
+
+    /// Let --health-check decide whether the record is proxied through Cloudflare, instead of
+    /// leaving the record alone when the check fails. While the service does not answer at the
+    /// IP the record is proxied, so visitors get Cloudflare's error page, and once it answers
+    /// again the record goes back to DNS only. The IP is updated either way.
+    #[arg(
+        long = "proxy-on-unhealthy",
+        requires = "health_check",
+        conflicts_with_all = ["proxied", "on_health_check_failure"],
+        help_heading = "Cloudflare"
+    )]
+    pub proxy_on_unhealthy: bool,
+
+    /// How long to wait for --health-check, in seconds.
+    #[arg(
+        long = "health-check-timeout",
+        value_name = "SECONDS",
+        default_value = "5"
+    )]
+    pub health_check_timeout: u16,
+
+    /// What to do when --health-check fails. "skip" leaves the record alone with a warning, and
+    /// "fail" exits with an error.
+    #[arg(
+        long = "on-health-check-failure",
+        value_enum,
+        default_value_t = HealthCheckFailure::Skip,
+        value_name = "ACTION"
+    )]
+    pub on_health_check_failure: HealthCheckFailure,
+
+    /// Shell command to run right before the record is written. The update only happens if it
+    /// exits successfully, and is vetoed with exit code 7 otherwise. The command gets the record
+    /// in DYNDNS_RECORD, its current IP in DYNDNS_OLD_IP (empty when creating it) and the
+    /// detected IP in DYNDNS_NEW_IP. It is not run with --dry-run.
+    #[arg(long = "pre-update-command", value_name = "COMMAND")]
+    pub pre_update_command: Option<String>,
+
+    /// When none of the IP services answer, continue with the last detected IP from the state
+    /// file instead of failing, as long as it is not older than --cached-ip-max-age. Requires
+    /// --state-file.
+    #[arg(
+        long = "use-cached-on-failure",
+        requires = "state_file",
+        help_heading = "IP"
+    )]
+    pub use_cached_on_failure: bool,
+
+    /// The oldest cached IP that --use-cached-on-failure may use. Accepts the same durations as
+    /// --update-at-most-every.
+    #[arg(
+        long = "cached-ip-max-age",
+        value_name = "DURATION",
+        value_parser = parse_duration,
+        default_value = "1h",
+        help_heading = "IP"
+    )]
+    pub cached_ip_max_age: Duration,
+
+    /// URL to send a GET request to at the end of every successful run, even when no change was
+    /// needed. Point this at a monitoring service to get alerted when updates stop running. A
+    /// failed ping only results in a warning.
+    #[arg(
+        long = "heartbeat-url",
+        value_name = "URL",
+        value_parser = parse_http_url,
+        help_heading = "Monitoring"
+    )]
+    pub heartbeat_url: Option<Url>,
+
+    /// healthchecks.io ping URL to report the outcome of every run to. Successful runs ping the
+    /// URL and failed runs ping its "/fail" endpoint, with a short description as the body. A
+    /// failed ping only results in a warning.
+    #[arg(
+        long = "healthcheck-url",
+        value_name = "URL",
+        value_parser = parse_http_url,
+        help_heading = "Monitoring"
+    )]
+    pub healthcheck_url: Option<Url>,
+
+    /// Prometheus Pushgateway to push the metrics of the run to at the end, for runs from cron.
+    /// The metrics tell whether the run succeeded, whether the record was changed, the exit code
+    /// and how long the run took. They are grouped by instance and record name. A failed push
+    /// only results in a warning.
+    #[arg(
+        long = "pushgateway-url",
+        value_name = "URL",
+        value_parser = parse_http_url,
+        help_heading = "Monitoring"
+    )]
+    pub pushgateway_url: Option<Url>,
+
+    /// The instance label for --pushgateway-url. Defaults to the host name.
+    #[arg(
+        long = "pushgateway-instance",
+        value_name = "NAME",
+        requires = "pushgateway_url",
+        help_heading = "Monitoring"
+    )]
+    pub pushgateway_instance: Option<String>,
+
+    /// URL to send a POST request to every time the record is changed. By default the body is a
+    /// JSON object with the record, old_ip, new_ip, zone and time. Can be given more than once to
+    /// notify several targets, which all get the same body. A failed notification only results
+    /// in a warning.
+    #[arg(
+        long = "notify-url",
+        value_name = "URL",
+        value_parser = parse_http_url,
+        help_heading = "Monitoring"
+    )]
+    pub notify_urls: Vec<Url>,
+
+    /// Template for the body of the notification, instead of the default JSON object. The
+    /// placeholders {record}, {old_ip}, {new_ip}, {zone} and {time} are replaced with their
+    /// values.
+    #[arg(
+        long = "notify-template",
+        value_name = "TEMPLATE",
+        value_parser = parse_template,
+        requires = "notify_urls",
+        help_heading = "Monitoring"
+    )]
+    pub notify_template: Option<String>,
+
+    /// The content type of the notification body.
+    #[arg(
+        long = "notify-content-type",
+        value_name = "TYPE",
+        default_value = "application/json",
+        help_heading = "Monitoring"
+    )]
+    pub notify_content_type: String,
+
+    /// How to pick the IP when not using --verify. "first" uses the first service that answers,
+    /// "fastest" races a few services and uses whichever answers first, and "consensus-lite"
+    /// requires a small quorum of services to agree.
+    #[arg(
+        long = "select-strategy",
+        value_enum,
+        default_value_t = SelectStrategy::First,
+        value_name = "STRATEGY",
+        conflicts_with = "verify",
+        help_heading = "IP"
+    )]
+    pub select_strategy: SelectStrategy,
+
+    /// Talk to all available IP services and check that an absolute majority of them have the same
+    /// answer before making any changes. Use this if you are extra paranoid and don't want a
+    /// hacked or buggy service to be able to give you the wrong IP back. The services are asked
+    /// at the same time, and the vote ends as soon as the remaining answers cannot change it.
+    #[arg(long = "verify", help_heading = "IP")]
+    pub verify: bool,
+
+    /// How many IP services --verify asks at the same time. The next service is asked as soon
+    /// as one of them answers.
+    #[arg(
+        long = "max-concurrent-ip-requests",
+        value_name = "COUNT",
+        default_value = "4",
+        value_parser = clap::value_parser!(u16).range(1..),
+        help_heading = "IP"
+    )]
+    pub max_concurrent_ip_requests: u16,
+
+    /// The share of the votes that an IP needs to be accepted when using --verify, as a fraction
+    /// between 0 and 1. For example "0.5" for a simple majority or "1" to require all services to
+    /// agree. Defaults to 2/3, rounded down to whole votes, so 2 of 4 votes are enough.
+    #[arg(
+        long = "verify-threshold",
+        value_name = "FRACTION",
+        value_parser = parse_fraction,
+        requires = "verify",
+        help_heading = "IP"
+    )]
+    pub verify_threshold: Option<f64>,
+}
+
+// Only a single instance is ever created, so the size difference doesn't matter.
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand, Clone, Debug)]
+pub enum Command {
+    /// Print the most recent updates from the history file.
+    History {
+        /// The number of entries to print.
+        #[arg(long = "limit", value_name = "COUNT", default_value = "10")]
+        limit: usize,
+    },
+
+    /// List the DNS records in the zone, to help find the name of the record to update.
+    ListRecords {
+        #[command(flatten)]
+        cloudflare: CloudflareOptions,
+
+        /// Only list records of this type ("A", "AAAA", "CNAME", …).
+        #[arg(long = "type", value_name = "TYPE")]
+        record_type: Option<String>,
+
+        /// Only list records with a name that contains this text.
+        #[arg(long = "name", value_name = "TEXT")]
+        name_contains: Option<String>,
+    },
+
+    /// Update the origin of a custom hostname from Cloudflare for SaaS, instead of a record given
+    /// by name. The origin is the custom origin server of the hostname, or the fallback origin of
+    /// the zone when it has none. The record of the origin must already exist in the zone.
+    ///
+    /// Apart from the global options like --dry-run and --verbose, the update uses the defaults
+    /// for every option.
+    SaasOrigin {
+        #[command(flatten)]
+        cloudflare: CloudflareOptions,
+
+        /// The ID of the custom hostname.
+        #[arg(long = "custom-hostname-id", value_name = "ID")]
+        custom_hostname_id: String,
+    },
+
+    /// Ask every IP service for the IP and report how each of them answered, to help build a list
+    /// for --ip-services-file. No DNS changes are made and no Cloudflare credentials are needed.
+    /// Use --output json for a machine-readable report.
+    ProbeServices {
+        /// Probe the IP services in this file instead of the built-in list.
+        #[arg(long = "ip-services-file", value_name = "PATH")]
+        ip_services_file: Option<PathBuf>,
+    },
+
+    /// Work with the Cloudflare API token.
+    Token {
+        #[command(subcommand)]
+        command: TokenCommand,
+    },
+
+    /// Print the version along with the git commit, Rust version and cargo features it was built
+    /// with. Use --output json for a machine-readable version.
+    Version,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum TokenCommand {
+    /// Check that the API token is valid, and print its status, ID and permissions without doing
+    /// any DNS changes. The token itself is never printed.
+    Verify {
+        /// The Cloudflare API token.
+        #[arg(
+            long = "token",
+            short = 't',
+            env = "CLOUDFLARE_API_TOKEN",
+            value_name = "TOKEN",
+            hide_env_values = true
+        )]
+        api_token: String,
+
+        /// Custom Cloudflare API base URL. Will use Cloudflare Production if not specified.
+        #[arg(
+            long = "cloudflare-api-url",
+            env = "CLOUDFLARE_API_URL",
+            value_name = "URL"
+        )]
+        base_url: Option<Url>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum IpFileFormat {
+    Bare,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum HealthCheckFailure {
+    Skip,
+    Fail,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SelectStrategy {
+    First,
+    Fastest,
+    ConsensusLite,
+}
+
+/// Overrides for the fields of the record, as read by --patch.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecordPatch {
+    pub content: Option<Ipv4Addr>,
+    pub ttl: Option<u32>,
+    pub proxied: Option<bool>,
+}
+
+// How to connect to Cloudflare, and which zone to work with.
+#[derive(Args, Clone, Debug)]
+pub struct CloudflareOptions {
+    /// The Cloudflare API token.
+    #[arg(
+        long = "token",
+        short = 't',
+        env = "CLOUDFLARE_API_TOKEN",
+        value_name = "TOKEN",
+        required = true,
+        help_heading = "Cloudflare"
+    )]
+    pub api_token: Option<String>,
+
+    #[command(flatten)]
+    pub zone_options: ZoneOptions,
+
+    /// Only look for the zone inside this Cloudflare account. Use this when the token has access
+    /// to zones with the same name in multiple accounts.
+    #[arg(
+        long = "account-id",
+        env = "CLOUDFLARE_ACCOUNT_ID",
+        value_name = "ID",
+        help_heading = "Cloudflare"
+    )]
+    pub account_id: Option<String>,
+
+    /// Custom Cloudflare API base URL. Will use Cloudflare Production if not specified.
+    #[arg(
+        long = "cloudflare-api-url",
+        env = "CLOUDFLARE_API_URL",
+        value_name = "URL",
+        help_heading = "Cloudflare"
+    )]
+    pub base_url: Option<Url>,
+
+    /// Connect to HOST at IP instead of looking it up in DNS, like "api.cloudflare.com:1.2.3.4".
+    /// Applies to the Cloudflare API and the IP services. Can be given more than once.
+    #[arg(
+        long = "resolve",
+        value_name = "HOST:IP",
+        value_parser = parse_host_override,
+        help_heading = "Cloudflare"
+    )]
+    pub resolves: Vec<HostOverride>,
+}
+
+/// A host name that is connected to at a fixed IP instead of being looked up, from --resolve.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HostOverride {
+    pub host: String,
+    pub ip: IpAddr,
+}
+
+// When neither is given, the zone is derived from the name of the record to update.
+#[derive(Args, Clone, Debug)]
+#[group(multiple = true)]
+pub struct ZoneOptions {
+    /// The name of the zone to update ("6d3cf337c06d898fc4743293fda5ea3a").
+    #[arg(
+        long = "zone-id",
+        env = "CLOUDFLARE_ZONE_ID",
+        value_name = "ID",
+        help_heading = "Cloudflare"
+    )]
+    pub id: Option<String>,
+
+    /// The name of the zone to update ("example.com"). If no Zone ID is set, then this name is
+    /// used to look up the Zone ID using the API. Without either, the zone is the longest zone
+    /// that the token can access which the record name ends with.
+    #[arg(
+        long = "zone-name",
+        env = "CLOUDFLARE_ZONE_NAME",
+        value_name = "NAME",
+        value_parser = parse_domain_name,
+        help_heading = "Cloudflare"
+    )]
+    pub name: Option<String>,
+}
+
+impl CloudflareOptions {
+    pub(crate) fn credentials(&self) -> Credentials {
+        Credentials::UserAuthToken {
+            token: self
+                .api_token
+                .clone()
+                .expect("Programmer error: Token is only optional for subcommands"),
+        }
+    }
+
+    pub(crate) fn environment(&self) -> Environment {
+        match &self.base_url {
+            Some(url) => Environment::Custom(url.to_owned()),
+            None => Environment::Production,
+        }
+    }
+
+    pub fn client(&self) -> Result<CloudflareClient, String> {
+        // The client can only pin the host of the API, and that is the only host it talks to.
+        let api_host = Url::from(&self.environment())
+            .host_str()
+            .map(|host| host.to_string());
+        let resolve_ip = self
+            .resolves
+            .iter()
+            .find(|resolve| Some(&resolve.host) == api_host.as_ref())
+            .map(|resolve| resolve.ip);
+
+        CloudflareClient::new(
+            self.credentials(),
+            HttpApiClientConfig {
+                resolve_ip,
+                ..HttpApiClientConfig::default()
+            },
+            self.environment(),
+        )
+        .map_err(|err| format!("Failed to initialize Cloudflare API client: {}", err))
+    }
+
+    /// Applies the --resolve overrides to an HTTP client.
+    pub(crate) fn resolve_hosts(&self, mut builder: ClientBuilder) -> ClientBuilder {
+        for resolve in &self.resolves {
+            // The port is ignored, and the one for the scheme of the URL is used.
+            builder = builder.resolve(&resolve.host, SocketAddr::new(resolve.ip, 0));
+        }
+        builder
+    }
+}
+
+/// How much is written to stderr, from --quiet to -vvv.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+    Debug,
+    Trace,
+}
+
+impl Options {
+    pub fn verbosity(&self) -> Verbosity {
+        match i16::from(self.verbose) - i16::from(self.quiet) {
+            level if level < 0 => Verbosity::Quiet,
+            0 => Verbosity::Normal,
+            1 => Verbosity::Verbose,
+            2 => Verbosity::Debug,
+            _ => Verbosity::Trace,
+        }
+    }
+
+    pub fn is_verbose(&self) -> bool {
+        self.verbosity() >= Verbosity::Verbose
+    }
+
+    pub fn is_quiet(&self) -> bool {
+        self.verbosity() == Verbosity::Quiet
+    }
+
+    /// The TTL to write, from --patch or --ttl. `None` keeps the current TTL, which is always the
+    /// case with --compare-ip-only.
+    pub fn wanted_ttl(&self) -> Option<u32> {
+        if self.compare_ip_only {
+            return None;
+        }
+        self.patch.and_then(|patch| patch.ttl).or(self.ttl)
+    }
+
+    /// Whether the record should be proxied, from --patch or --proxied. `None` keeps the current
+    /// setting, which is always the case with --compare-ip-only.
+    pub fn wanted_proxied(&self) -> Option<bool> {
+        if self.compare_ip_only {
+            return None;
+        }
+        self.patch.and_then(|patch| patch.proxied).or(self.proxied)
+    }
+
+    /// The name of the record to update, which is the zone name when no record is given. With
+    /// --record-suffix, this is the suffix.
+    pub fn dns_record(&self) -> &str {
+        self.dns_record
+            .as_deref()
+            .or(self.record_suffix.as_deref())
+            .or(self.cloudflare.zone_options.name.as_deref())
+            .expect("Programmer error: Record is only optional for subcommands or with a zone name")
+    }
+}
+
+/// Converts a domain name into the ASCII form that Cloudflare uses, so internationalized names
+/// ("exämple.com") can be compared against the punycode names ("xn--exmple-cua.com") returned
+/// by the API.
+fn parse_domain_name(name: &str) -> Result<String, String> {
+    idna::domain_to_ascii(name).map_err(|err| format!("Invalid domain name {}: {}", name, err))
+}
+
+/// Parses a URL that requests are sent to, which has to be an HTTP(S) URL.
+fn parse_http_url(string: &str) -> Result<Url, String> {
+    let url: Url = string
+        .parse()
+        .map_err(|err| format!("Invalid URL {}: {}", string, err))?;
+
+    match url.scheme() {
+        "http" | "https" => Ok(url),
+        scheme => Err(format!(
+            "Invalid URL {}: Unsupported scheme {}",
+            string, scheme
+        )),
+    }
+}
+
+/// Converts a --record-suffix like `parse_domain_name`, keeping the leading dot if it has one.
+fn parse_record_suffix(suffix: &str) -> Result<String, String> {
+    match suffix.strip_prefix('.') {
+        Some(name) => parse_domain_name(name).map(|name| format!(".{}", name)),
+        None => parse_domain_name(suffix),
+    }
+}
+
+/// Parses a duration like "90", "30s", "15m", "12h" or "1d". A number without a unit is in
+/// seconds.
+fn parse_duration(string: &str) -> Result<Duration, String> {
+    let string = string.trim();
+    let (number, multiplier) = match string.char_indices().last() {
+        Some((index, 's')) => (&string[..index], 1),
+        Some((index, 'm')) => (&string[..index], 60),
+        Some((index, 'h')) => (&string[..index], 60 * 60),
+        Some((index, 'd')) => (&string[..index], 24 * 60 * 60),
+        _ => (string, 1),
+    };
+
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .map(Duration::from_secs)
+        .ok_or_else(|| {
+            format!(
+                "Invalid duration {:?}. Use a number of seconds, or a number followed by s, m, h or d.",
+                string
+            )
+        })
+}
+
+/// Parses a TTL into the number of seconds that Cloudflare expects, where 1 means automatic.
+fn parse_ttl(string: &str) -> Result<u32, String> {
+    if string.trim().eq_ignore_ascii_case("auto") {
+        return Ok(1);
+    }
+
+    let seconds = parse_duration(string)?.as_secs();
+    if (MIN_TTL..=MAX_TTL).contains(&seconds) {
+        Ok(seconds as u32)
+    } else {
+        Err(format!(
+            "Cloudflare does not accept a TTL of {} second(s). Use \"auto\", or a TTL from 1m \
+             (30s on Enterprise plans) to 1d.",
+            seconds
+        ))
+    }
+}
+
+/// Reads the JSON object for --patch from the file, or from stdin if the path is "-".
+fn parse_patch(path: &str) -> Result<RecordPatch, String> {
+    let (json, source) = if path == "-" {
+        let mut json = String::new();
+        io::stdin()
+            .read_to_string(&mut json)
+            .map_err(|err| format!("Failed to read patch from stdin: {}", err))?;
+        (json, String::from("stdin"))
+    } else {
+        let json = fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read patch file {}: {}", path, err))?;
+        (json, path.to_string())
+    };
+
+    let patch: RecordPatch = serde_json::from_str(&json)
+        .map_err(|err| format!("Invalid patch from {}: {}", source, err))?;
+    if let Some(ttl) = patch.ttl {
+        if ttl != 1 && !(MIN_TTL..=MAX_TTL).contains(&u64::from(ttl)) {
+            return Err(format!(
+                "Invalid patch from {}: Cloudflare does not accept a TTL of {} second(s). Use 1 \
+                 for automatic, or a TTL from 60 (30 on Enterprise plans) to 86400.",
+                source, ttl
+            ));
+        }
+    }
+
+    Ok(patch)
+}
+
+/// Parses "HOST:IP". The IP may be an IPv6 address, which has colons of its own.
+fn parse_host_override(string: &str) -> Result<HostOverride, String> {
+    let invalid = || {
+        format!(
+            "Invalid override {:?}. Use HOST:IP, like api.cloudflare.com:192.0.2.1.",
+            string
+        )
+    };
+
+    let (host, ip) = string.split_once(':').ok_or_else(invalid)?;
+    let ip = ip.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(invalid());
+    }
+
+    Ok(HostOverride {
+        host: parse_domain_name(host)?,
+        ip: ip.parse().map_err(|_| invalid())?,
+    })
+}
+
+/// Parses an AS number, with or without the "AS" prefix.
+fn parse_asn(string: &str) -> Result<u32, String> {
+    let string = string.trim();
+    let number = string
+        .strip_prefix("AS")
+        .or_else(|| string.strip_prefix("as"))
+        .unwrap_or(string);
+
+    number
+        .parse()
+        .map_err(|_| format!("Invalid AS number {:?}. Use a number like 64500.", string))
+}
+
+fn parse_fraction(string: &str) -> Result<f64, String> {
+    match string.trim().parse::<f64>() {
+        Ok(fraction) if fraction > 0.0 && fraction <= 1.0 => Ok(fraction),
+        _ => Err(format!(
+            "Invalid fraction {:?}. Use a number larger than 0 and at most 1, like 0.5.",
+            string
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations_with_units() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(5 * 60)));
+        assert_eq!(parse_duration(" 2h "), Ok(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(24 * 60 * 60)));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        for input in [
+            "",
+            "m",
+            "5 m",
+            "-5m",
+            "1.5h",
+            "5w",
+            "five",
+            "18446744073709551615d",
+        ] {
+            assert!(parse_duration(input).is_err(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn parses_auto_ttl() {
+        assert_eq!(parse_ttl("auto"), Ok(1));
+        assert_eq!(parse_ttl(" AUTO "), Ok(1));
+    }
+
+    #[test]
+    fn accepts_ttls_within_the_bounds() {
+        assert_eq!(parse_ttl("30"), Ok(30));
+        assert_eq!(parse_ttl("1m"), Ok(60));
+        assert_eq!(parse_ttl("1d"), Ok(86400));
+        assert_eq!(parse_ttl("86400s"), Ok(86400));
+    }
+
+    #[test]
+    fn rejects_ttls_outside_the_bounds() {
+        for input in ["0", "1", "29", "86401", "2d"] {
+            assert!(parse_ttl(input).is_err(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_ttls() {
+        for input in ["", "automatic", "1h30m", "-60"] {
+            assert!(parse_ttl(input).is_err(), "{:?}", input);
+        }
+    }
+}
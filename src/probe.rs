@@ -0,0 +1,134 @@
+//! Asks every IP service for the IP to see how well they work, for the `probe-services`
+//! subcommand.
+
+use crate::ip::ip_services;
+use crate::options::{Options, OutputFormat};
+use crate::source::{HttpEchoSource, SourceError};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::thread;
+use std::time::Instant;
+
+#[derive(Serialize, Debug)]
+struct Probe {
+    service: String,
+    reachable: bool,
+    status: Option<u16>,
+    time_ms: u128,
+    ip: Option<Ipv4Addr>,
+    error: Option<String>,
+    agrees_with_majority: Option<bool>,
+}
+
+/// Asks all the IP services at the same time and prints how each of them answered on stdout,
+/// without doing any DNS changes.
+pub fn probe_services(options: &Options) -> Result<(), String> {
+    let handles = ip_services(options)?
+        .into_iter()
+        .map(|url| HttpEchoSource::new(options, url))
+        .collect::<Result<Vec<HttpEchoSource>, String>>()?
+        .into_iter()
+        .map(|source| {
+            thread::spawn(move || {
+                let start = Instant::now();
+                let (status, reply) = source.fetch_ip_with_status();
+                (source, status, start.elapsed(), reply)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut probes: Vec<Probe> = handles
+        .into_iter()
+        .map(|handle| {
+            let (source, status, elapsed, reply) = handle
+                .join()
+                .expect("Programmer error: Probe thread panicked");
+            Probe {
+                service: source.url().to_string(),
+                reachable: !matches!(reply, Err(SourceError::RequestFailed(_))),
+                status: status.map(|status| status.as_u16()),
+                time_ms: elapsed.as_millis(),
+                ip: reply.as_ref().ok().copied(),
+                error: reply.err().map(|err| err.to_string()),
+                agrees_with_majority: None,
+            }
+        })
+        .collect();
+
+    if let Some(majority) = majority_ip(&probes) {
+        for probe in probes.iter_mut() {
+            probe.agrees_with_majority = probe.ip.map(|ip| ip == majority);
+        }
+    }
+
+    match options.output {
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&probes)
+                .map_err(|err| format!("Failed to serialize probes: {}", err))?
+        ),
+        OutputFormat::Text => print_probes_table(&probes),
+    }
+
+    Ok(())
+}
+
+/// The IP that most services found. Ties go to the IP that was found by the earliest service in
+/// the list.
+fn majority_ip(probes: &[Probe]) -> Option<Ipv4Addr> {
+    let mut votes: HashMap<Ipv4Addr, usize> = HashMap::new();
+    for ip in probes.iter().filter_map(|probe| probe.ip) {
+        *votes.entry(ip).or_insert(0) += 1;
+    }
+
+    let most_votes = votes.values().copied().max()?;
+    probes
+        .iter()
+        .filter_map(|probe| probe.ip)
+        .find(|ip| votes[ip] == most_votes)
+}
+
+fn print_probes_table(probes: &[Probe]) {
+    let rows: Vec<[String; 5]> = probes
+        .iter()
+        .map(|probe| {
+            [
+                probe.service.clone(),
+                probe
+                    .status
+                    .map_or_else(|| String::from("-"), |status| status.to_string()),
+                format!("{} ms", probe.time_ms),
+                match probe.agrees_with_majority {
+                    Some(true) => String::from("yes"),
+                    Some(false) => String::from("no"),
+                    None => String::from("-"),
+                },
+                // Errors are last since they can be long.
+                match (&probe.ip, &probe.error) {
+                    (Some(ip), _) => ip.to_string(),
+                    (None, Some(error)) => error.lines().next().unwrap_or_default().to_string(),
+                    (None, None) => String::from("-"),
+                },
+            ]
+        })
+        .collect();
+
+    let header = ["SERVICE", "STATUS", "TIME", "MAJORITY", "IP"].map(String::from);
+    let mut widths = header.clone().map(|column| column.len());
+    for row in rows.iter() {
+        for (width, column) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(column.chars().count());
+        }
+    }
+
+    for row in Some(&header).into_iter().chain(rows.iter()) {
+        let line = row
+            .iter()
+            .zip(widths.iter())
+            .map(|(column, width)| format!("{:<width$}", column, width = width))
+            .collect::<Vec<String>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+}
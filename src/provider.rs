@@ -0,0 +1,77 @@
+//! Other DNS providers that the record is kept in sync with, next to Cloudflare.
+
+use crate::ip::http_client;
+use crate::options::Options;
+use reqwest::blocking::Client;
+use reqwest::Url;
+use std::net::Ipv4Addr;
+
+/// A DNS provider that gets the new IP whenever the Cloudflare record is updated.
+pub trait DnsProvider {
+    /// A short description of the provider, used in messages.
+    fn name(&self) -> String;
+
+    /// Makes the record at the provider point to the IP.
+    fn update_record(&self, record: &str, ip: Ipv4Addr) -> Result<(), String>;
+}
+
+/// A provider that is updated by sending a GET request to a URL, which is how most dynamic DNS
+/// services work. The placeholders `{record}` and `{ip}` in the URL are replaced with their
+/// values.
+pub struct HttpProvider {
+    template: String,
+    client: Client,
+}
+
+impl HttpProvider {
+    pub fn new(options: &Options, template: &str) -> Result<HttpProvider, String> {
+        let template = parse_url_template(template)?;
+        let client = http_client(options)?;
+
+        Ok(HttpProvider { template, client })
+    }
+
+    fn url(&self, record: &str, ip: Ipv4Addr) -> Url {
+        render_url(&self.template, record, ip)
+            .expect("Programmer error: URL template was not validated")
+    }
+}
+
+impl DnsProvider for HttpProvider {
+    fn name(&self) -> String {
+        // Only the host is shown, since the rest of the URL often contains credentials.
+        let url = self.url("example.com", Ipv4Addr::UNSPECIFIED);
+        url.host_str().unwrap_or("unknown host").to_string()
+    }
+
+    fn update_record(&self, record: &str, ip: Ipv4Addr) -> Result<(), String> {
+        self.client
+            .get(self.url(record, ip))
+            .send()
+            .and_then(|response| response.error_for_status())
+            .map(|_| ())
+            .map_err(|err| err.without_url().to_string())
+    }
+}
+
+/// Checks that the template turns into a valid HTTP URL once the placeholders are replaced.
+pub(crate) fn parse_url_template(template: &str) -> Result<String, String> {
+    let url = render_url(template, "example.com", Ipv4Addr::UNSPECIFIED)
+        .map_err(|err| format!("Invalid URL {}: {}", template, err))?;
+
+    match url.scheme() {
+        "http" | "https" => Ok(template.to_string()),
+        scheme => Err(format!(
+            "Invalid URL {}: Unsupported scheme {}",
+            template, scheme
+        )),
+    }
+}
+
+fn render_url(template: &str, record: &str, ip: Ipv4Addr) -> Result<Url, String> {
+    template
+        .replace("{record}", record)
+        .replace("{ip}", &ip.to_string())
+        .parse::<Url>()
+        .map_err(|err| err.to_string())
+}
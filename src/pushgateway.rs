@@ -0,0 +1,124 @@
+//! Pushes the metrics of a run to a Prometheus Pushgateway, for runs from cron that have nowhere
+//! to be scraped from.
+
+use crate::color::paint_warning;
+use crate::error::Error;
+use crate::ip::http_client;
+use crate::options::Options;
+use crate::state::unix_timestamp;
+use crate::Outcome;
+use reqwest::Url;
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::time::Duration;
+
+/// The job that the metrics are pushed as.
+const JOB: &str = "cloudflare_dyndns";
+
+/// Pushes the outcome of the run to `--pushgateway-url`, grouped by the instance and the record
+/// so that runs for other records or hosts do not replace each other's metrics. A failed push
+/// only results in a warning.
+pub fn push_metrics(
+    options: &Options,
+    url: &Url,
+    result: &Result<Outcome, Error>,
+    elapsed: Duration,
+) {
+    let instance = options
+        .pushgateway_instance
+        .clone()
+        .unwrap_or_else(hostname);
+    let mut push_url = url.clone();
+    push_url
+        .path_segments_mut()
+        .map(|mut segments| {
+            segments.pop_if_empty().extend([
+                "metrics",
+                "job",
+                JOB,
+                "instance",
+                &instance,
+                "record",
+                options.dns_record(),
+            ]);
+        })
+        .expect("Programmer error: Pushgateway URLs are always HTTP URLs");
+
+    let result = http_client(options).and_then(|client| {
+        client
+            .put(push_url)
+            .body(render_metrics(result, elapsed))
+            .send()
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| err.to_string())
+    });
+
+    match result {
+        Ok(_) => {
+            if options.is_verbose() {
+                eprintln!("Pushed the metrics to the Pushgateway");
+            }
+        }
+        Err(err) => eprintln!(
+            "{} Failed to push the metrics to the Pushgateway: {}",
+            paint_warning("Warning:"),
+            err
+        ),
+    }
+}
+
+/// Renders the metrics in the Prometheus text format.
+fn render_metrics(result: &Result<Outcome, Error>, elapsed: Duration) -> String {
+    let (success, updated, exit_code) = match result {
+        Ok(outcome) => (true, outcome.updated, 0),
+        Err(error) => (false, false, error.exit_code()),
+    };
+
+    let metrics: [(&str, &str, String); 5] = [
+        (
+            "last_run_success",
+            "Whether the last run succeeded.",
+            u8::from(success).to_string(),
+        ),
+        (
+            "last_run_updated",
+            "Whether the last run changed the record.",
+            u8::from(updated).to_string(),
+        ),
+        (
+            "last_run_exit_code",
+            "The exit code of the last run.",
+            exit_code.to_string(),
+        ),
+        (
+            "last_run_duration_seconds",
+            "How long the last run took.",
+            elapsed.as_secs_f64().to_string(),
+        ),
+        (
+            "last_run_timestamp_seconds",
+            "When the last run finished, in seconds since the Unix epoch.",
+            unix_timestamp().to_string(),
+        ),
+    ];
+
+    let mut body = String::new();
+    for (name, help, value) in metrics.iter() {
+        // Writing to a String cannot fail.
+        let _ = writeln!(body, "# HELP {}_{} {}", JOB, name, help);
+        let _ = writeln!(body, "# TYPE {}_{} gauge", JOB, name);
+        let _ = writeln!(body, "{}_{} {}", JOB, name, value);
+    }
+    body
+}
+
+/// The name of this host, which is the default instance label.
+fn hostname() -> String {
+    env::var("HOSTNAME")
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| String::from("unknown"))
+}
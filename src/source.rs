@@ -0,0 +1,302 @@
+//! The sources that the external IP can be asked from.
+
+use crate::ip::{http_client, Ipv4Range};
+use crate::options::Options;
+use crate::upnp::external_ip_from_router;
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use reqwest::blocking::Client;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{StatusCode, Url};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+const IPV4_MATCHER: &str = r"\b\d{1,3}(\.\d{1,3}){3}\b";
+const BODY_SNIPPET_LENGTH: usize = 80;
+
+/// Something that can tell the external IP, like an IP service or the router.
+///
+/// Sources are asked from several threads at the same time when voting with `verify`.
+pub trait IpSource: Send + Sync {
+    /// A short description of the source, used in messages and as the key of its health in the
+    /// state file.
+    fn name(&self) -> String;
+
+    /// Asks the source for the external IP.
+    fn fetch_ip(&self) -> Result<Ipv4Addr, SourceError>;
+}
+
+/// Why a source did not return an IP.
+#[derive(Debug)]
+pub enum SourceError {
+    /// The reply did not contain an IP.
+    NoIpInBody,
+    /// The IP was excluded by --exclude-ip or --exclude-range.
+    Excluded(Ipv4Addr),
+    /// The service is rate limiting the requests, and asked to wait this long before the next one
+    /// if it said so.
+    RateLimited(Option<Duration>),
+    /// The reply had an unsuccessful status, along with a snippet of its body.
+    HttpStatus(StatusCode, String),
+    /// The request could not be sent, or the reply could not be read.
+    RequestFailed(reqwest::Error),
+    /// Any other failure, described by the message.
+    Failed(String),
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SourceError::NoIpInBody => f.write_str("No IP found in response."),
+            SourceError::Excluded(ip) => write!(f, "{} is excluded.", ip),
+            SourceError::RateLimited(Some(delay)) => {
+                write!(f, "Rate limited, retry after {}s.", delay.as_secs())
+            }
+            SourceError::RateLimited(None) => f.write_str("Rate limited."),
+            // The snippet is left out, since it is only shown at trace level.
+            SourceError::HttpStatus(status, _snippet) => write!(f, "HTTP {}", status.as_u16()),
+            SourceError::RequestFailed(err) => write!(f, "{}", err),
+            SourceError::Failed(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for SourceError {}
+
+/// An IP service that replies with the IP of whoever sent the request, somewhere in the body.
+pub struct HttpEchoSource {
+    url: Url,
+    client: Client,
+    extractor: IpExtractor,
+}
+
+impl HttpEchoSource {
+    pub fn new(options: &Options, url: Url) -> Result<HttpEchoSource, String> {
+        Ok(HttpEchoSource {
+            url,
+            client: http_client(options)?,
+            extractor: IpExtractor::new(options),
+        })
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Asks the service for the IP like `fetch_ip`, but also returns the status of the reply when
+    /// there was one.
+    pub(crate) fn fetch_ip_with_status(
+        &self,
+    ) -> (Option<StatusCode>, Result<Ipv4Addr, SourceError>) {
+        let (status, retry_after, body) = match fetch_body(&self.client, &self.url) {
+            Ok(reply) => reply,
+            // Some services drop the connection halfway through the body every now and then, so
+            // give them one more chance.
+            Err(BodyError::Read(_)) => match fetch_body(&self.client, &self.url) {
+                Ok(reply) => reply,
+                Err(BodyError::Request(err)) | Err(BodyError::Read(err)) => {
+                    return (None, Err(SourceError::RequestFailed(err)))
+                }
+            },
+            Err(BodyError::Request(err)) => return (None, Err(SourceError::RequestFailed(err))),
+        };
+
+        (Some(status), self.parse_reply(status, retry_after, &body))
+    }
+
+    fn parse_reply(
+        &self,
+        status: StatusCode,
+        retry_after: Option<Duration>,
+        body: &str,
+    ) -> Result<Ipv4Addr, SourceError> {
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            return Err(SourceError::RateLimited(retry_after));
+        }
+        if !status.is_success() {
+            return Err(SourceError::HttpStatus(status, body_snippet(body)));
+        }
+
+        let ip = self
+            .extractor
+            .extract_ip(body)
+            .ok_or(SourceError::NoIpInBody)?;
+        let ip: Ipv4Addr = ip
+            .parse()
+            .map_err(|err| SourceError::Failed(format!("Invalid IP address {}: {}", ip, err)))?;
+
+        if self.extractor.is_excluded(ip) {
+            Err(SourceError::Excluded(ip))
+        } else {
+            Ok(ip)
+        }
+    }
+}
+
+impl IpSource for HttpEchoSource {
+    fn name(&self) -> String {
+        self.url.to_string()
+    }
+
+    fn fetch_ip(&self) -> Result<Ipv4Addr, SourceError> {
+        self.fetch_ip_with_status().1
+    }
+}
+
+/// The router on the local network, asked over UPnP.
+pub struct UpnpSource {
+    options: Options,
+}
+
+impl UpnpSource {
+    pub fn new(options: &Options) -> UpnpSource {
+        UpnpSource {
+            options: options.clone(),
+        }
+    }
+}
+
+impl IpSource for UpnpSource {
+    fn name(&self) -> String {
+        String::from("UPnP router")
+    }
+
+    fn fetch_ip(&self) -> Result<Ipv4Addr, SourceError> {
+        external_ip_from_router(&self.options).map_err(SourceError::Failed)
+    }
+}
+
+/// Finds the IP in the body of a reply from an IP service.
+struct IpExtractor {
+    matcher: Regex,
+    excluded_ips: Vec<Ipv4Addr>,
+    excluded_ranges: Vec<Ipv4Range>,
+}
+
+impl IpExtractor {
+    fn new(options: &Options) -> IpExtractor {
+        IpExtractor {
+            matcher: options.ip_regex.clone().unwrap_or_else(|| {
+                IPV4_MATCHER
+                    .parse()
+                    .expect("Programmer error: Invalid regexp")
+            }),
+            excluded_ips: options.exclude_ips.clone(),
+            excluded_ranges: options.exclude_ranges.clone(),
+        }
+    }
+
+    fn extract_ip(&self, body: &str) -> Option<String> {
+        self.matcher
+            .captures(body)
+            .map(|captures| captures[0].to_string())
+    }
+
+    /// Checks if the IP is one that some services are known to report wrongly, like the address
+    /// of a transparent proxy.
+    fn is_excluded(&self, ip: Ipv4Addr) -> bool {
+        self.excluded_ips.contains(&ip)
+            || self.excluded_ranges.iter().any(|range| range.contains(ip))
+    }
+}
+
+pub(crate) fn parse_ip_regex(pattern: &str) -> Result<Regex, String> {
+    Regex::new(pattern).map_err(|err| format!("Invalid regex {}: {}", pattern, err))
+}
+
+/// Where a request to an IP service failed.
+enum BodyError {
+    Request(reqwest::Error),
+    Read(reqwest::Error),
+}
+
+/// Sends the request and reads the reply, returning its status, how long its `Retry-After`
+/// header asks to wait, and its body.
+fn fetch_body(
+    client: &Client,
+    url: &Url,
+) -> Result<(StatusCode, Option<Duration>, String), BodyError> {
+    let response = client.get(url.clone()).send().map_err(BodyError::Request)?;
+    let status = response.status();
+    let retry_after = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_retry_after);
+    let body = response.text().map_err(BodyError::Read)?;
+
+    Ok((status, retry_after, body))
+}
+
+/// Parses a `Retry-After` header, which is either a number of seconds or an HTTP date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    let seconds = (date.with_timezone(&Utc) - Utc::now()).num_seconds();
+    Some(Duration::from_secs(seconds.max(0) as u64))
+}
+
+/// Collapses all whitespace in the body and cuts it down to a short, single-line snippet that is
+/// suitable for log output.
+fn body_snippet(body: &str) -> String {
+    let collapsed = body.split_whitespace().collect::<Vec<&str>>().join(" ");
+
+    if collapsed.chars().count() > BODY_SNIPPET_LENGTH {
+        let mut snippet: String = collapsed.chars().take(BODY_SNIPPET_LENGTH).collect();
+        snippet.push('…');
+        snippet
+    } else {
+        collapsed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn http_date(offset: chrono::Duration) -> String {
+        (Utc::now() + offset)
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string()
+    }
+
+    #[test]
+    fn parses_retry_after_in_seconds() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 "), Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn parses_retry_after_as_an_http_date() {
+        let delay = parse_retry_after(&http_date(chrono::Duration::hours(1))).unwrap();
+        assert!(
+            (3590..=3600).contains(&delay.as_secs()),
+            "{}",
+            delay.as_secs()
+        );
+    }
+
+    #[test]
+    fn clamps_a_retry_after_date_in_the_past_to_zero() {
+        assert_eq!(
+            parse_retry_after(&http_date(chrono::Duration::hours(-1))),
+            Some(Duration::from_secs(0))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::from_secs(0))
+        );
+    }
+
+    #[test]
+    fn ignores_an_invalid_retry_after() {
+        for input in ["", "-5", "1.5", "soon", "2015-10-21T07:28:00Z"] {
+            assert_eq!(parse_retry_after(input), None, "{:?}", input);
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! The state file, which keeps bookkeeping between runs.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Bookkeeping that is kept in the state file between runs.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub(crate) struct State {
+    /// When the record was last written to, in seconds since the Unix epoch.
+    pub(crate) last_update: Option<u64>,
+    /// The last IP that was detected successfully.
+    pub(crate) last_ip: Option<Ipv4Addr>,
+    /// When `last_ip` was detected, in seconds since the Unix epoch.
+    pub(crate) last_ip_detected: Option<u64>,
+    /// How many runs in a row have detected `last_ip`, for --confirm-count.
+    pub(crate) ip_streak: u32,
+    /// How well each IP service has answered in earlier runs, by URL.
+    pub(crate) service_health: BTreeMap<String, ServiceHealth>,
+}
+
+/// How many answers to remember per service before the older ones start to count for less, so
+/// that a service that has been fixed (or broken) moves up (or down) again.
+const SERVICE_HEALTH_WINDOW: u32 = 50;
+
+/// How often an IP service has found an IP, and how often it has not.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+pub(crate) struct ServiceHealth {
+    pub(crate) successes: u32,
+    pub(crate) failures: u32,
+}
+
+impl ServiceHealth {
+    /// The estimated chance that the service finds an IP, from 0 to 1. Services without any
+    /// history are at 0.5.
+    pub(crate) fn score(&self) -> f64 {
+        f64::from(self.successes + 1) / f64::from(self.successes + self.failures + 2)
+    }
+
+    pub(crate) fn record(&mut self, success: bool) {
+        if success {
+            self.successes += 1;
+        } else {
+            self.failures += 1;
+        }
+
+        if self.successes + self.failures > SERVICE_HEALTH_WINDOW {
+            self.successes /= 2;
+            self.failures /= 2;
+        }
+    }
+}
+
+pub(crate) fn load_state(path: &Path) -> Result<State, String> {
+    match fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .map_err(|err| format!("Failed to parse state file {}: {}", path.display(), err)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(State::default()),
+        Err(err) => Err(format!(
+            "Failed to read state file {}: {}",
+            path.display(),
+            err
+        )),
+    }
+}
+
+pub(crate) fn save_state(path: &Path, state: &State) -> Result<(), String> {
+    let contents = serde_json::to_string_pretty(state)
+        .map_err(|err| format!("Failed to serialize state: {}", err))?;
+
+    write_atomically(path, &contents)
+        .map_err(|err| format!("Failed to write state file {}: {}", path.display(), err))
+}
+
+/// Writes to a temporary file first and then renames it into place, so that readers never see a
+/// half-written file.
+pub(crate) fn write_atomically(path: &Path, contents: &str) -> io::Result<()> {
+    let mut temporary_path = path.as_os_str().to_owned();
+    temporary_path.push(".tmp");
+    let temporary_path = PathBuf::from(temporary_path);
+
+    fs::write(&temporary_path, contents).and_then(|_| fs::rename(&temporary_path, path))
+}
+
+/// How many seconds have passed since the timestamp from the state file. Returns `None` when the
+/// timestamp is in the future, which means that the clock has been set back since it was taken
+/// and there is no telling how much time has really passed.
+pub(crate) fn seconds_since(timestamp: u64) -> Option<u64> {
+    unix_timestamp().checked_sub(timestamp)
+}
+
+pub(crate) fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
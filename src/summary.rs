@@ -0,0 +1,67 @@
+//! The recap of a run that is printed at the end with --verbose or --summary.
+
+use crate::options::{Options, OutputFormat};
+use serde::Serialize;
+use std::net::Ipv4Addr;
+
+/// What happened during a run.
+#[derive(Serialize, Debug, Default)]
+pub(crate) struct Summary {
+    pub(crate) services_queried: usize,
+    pub(crate) services_succeeded: usize,
+    /// How many different IPs the services that succeeded answered with.
+    pub(crate) distinct_ips: usize,
+    /// Where the zone ID came from: "flag" for --zone-id, "api" for a lookup of --zone-name, or
+    /// "derived" when it was derived from the record name.
+    pub(crate) zone_source: Option<&'static str>,
+    pub(crate) ip: Option<Ipv4Addr>,
+    pub(crate) updated: bool,
+    pub(crate) success: bool,
+    pub(crate) elapsed_ms: u128,
+}
+
+impl Summary {
+    /// Prints the summary on stderr, as a single line of JSON with --output json.
+    pub(crate) fn print(&self, options: &Options) {
+        if options.output == OutputFormat::Json {
+            match serde_json::to_string(self) {
+                Ok(json) => eprintln!("{}", json),
+                Err(err) => eprintln!("Failed to serialize summary: {}", err),
+            }
+            return;
+        }
+
+        let agreement = match self.distinct_ips {
+            0 => "",
+            1 => ", all agreed",
+            _ => ", disagreed",
+        };
+        let zone = match self.zone_source {
+            Some("flag") => "from --zone-id",
+            Some("derived") => "derived from the record name",
+            Some(_) => "looked up by name",
+            None => "not resolved",
+        };
+        let record = if !self.success {
+            "failed"
+        } else if self.updated {
+            "updated"
+        } else if options.dry_run {
+            "not written (dry run)"
+        } else {
+            "unchanged"
+        };
+
+        eprintln!("Summary:");
+        eprintln!(
+            "  IP services: {} queried, {} succeeded{}",
+            self.services_queried, self.services_succeeded, agreement
+        );
+        if let Some(ip) = self.ip {
+            eprintln!("  IP:          {}", ip);
+        }
+        eprintln!("  Zone:        {}", zone);
+        eprintln!("  Record:      {}", record);
+        eprintln!("  Elapsed:     {} ms", self.elapsed_ms);
+    }
+}
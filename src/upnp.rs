@@ -0,0 +1,168 @@
+//! Asking the router for its external IP using UPnP IGD (Internet Gateway Device).
+
+use crate::ip::http_client;
+use crate::options::Options;
+use regex::Regex;
+use reqwest::Url;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+const SSDP_ADDRESS: &str = "239.255.255.250:1900";
+const SSDP_SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+/// The services of an IGD that can tell the external IP, depending on how the router connects.
+const WAN_SERVICE_TYPES: [&str; 2] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+/// Finds the router on the local network and asks it for its external IP.
+pub(crate) fn external_ip_from_router(options: &Options) -> Result<Ipv4Addr, String> {
+    let timeout = Duration::from_secs(options.ip_timeout.into());
+
+    let location = discover_gateway(options.source_addr, timeout)?;
+
+    let client = http_client(options)?;
+    let description = client
+        .get(location.clone())
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.text())
+        .map_err(|err| format!("Failed to read the router's device description: {}", err))?;
+
+    let (service_type, control_url) = find_wan_service(&description).ok_or_else(|| {
+        String::from("The router does not offer a WAN connection service over UPnP.")
+    })?;
+    let control_url = location
+        .join(&control_url)
+        .map_err(|err| format!("Invalid UPnP control URL {}: {}", control_url, err))?;
+
+    let envelope = format!(
+        "<?xml version=\"1.0\"?>\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:GetExternalIPAddress xmlns:u=\"{}\"/></s:Body></s:Envelope>",
+        service_type
+    );
+    let reply = client
+        .post(control_url)
+        .header("Content-Type", "text/xml; charset=\"utf-8\"")
+        .header(
+            "SOAPAction",
+            format!("\"{}#GetExternalIPAddress\"", service_type),
+        )
+        .body(envelope)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.text())
+        .map_err(|err| format!("Failed to ask the router for its external IP: {}", err))?;
+
+    let ip = xml_element(&reply, "NewExternalIPAddress")
+        .ok_or_else(|| String::from("The router did not return an external IP."))?;
+    let ip: Ipv4Addr = ip
+        .parse()
+        .map_err(|err| format!("The router returned an invalid IP {}: {}", ip, err))?;
+
+    if ip.is_private() || ip.is_unspecified() {
+        return Err(format!(
+            "The router's external IP {} is not a public address. Is it behind another router?",
+            ip
+        ));
+    }
+
+    Ok(ip)
+}
+
+/// Sends an SSDP search for Internet Gateway Devices and returns the location of the device
+/// description of the first one that answers.
+fn discover_gateway(source_addr: Option<IpAddr>, timeout: Duration) -> Result<Url, String> {
+    let local_address = SocketAddr::new(source_addr.unwrap_or(Ipv4Addr::UNSPECIFIED.into()), 0);
+    let socket = UdpSocket::bind(local_address)
+        .map_err(|err| format!("Failed to open a socket for UPnP discovery: {}", err))?;
+
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\n\
+         HOST: {}\r\n\
+         MAN: \"ssdp:discover\"\r\n\
+         MX: 2\r\n\
+         ST: {}\r\n\r\n",
+        SSDP_ADDRESS, SSDP_SEARCH_TARGET
+    );
+    socket
+        .send_to(search.as_bytes(), SSDP_ADDRESS)
+        .map_err(|err| format!("Failed to send UPnP discovery request: {}", err))?;
+
+    let deadline = Instant::now() + timeout;
+    let mut buffer = [0; 2048];
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return Err(format!(
+                "No UPnP Internet Gateway Device answered within {} second(s). Is UPnP enabled on \
+                 the router?",
+                timeout.as_secs()
+            ));
+        }
+        socket
+            .set_read_timeout(Some(remaining))
+            .map_err(|err| format!("Failed to wait for UPnP replies: {}", err))?;
+
+        // Timeouts end up as errors here, and are then reported by the check above.
+        let length = match socket.recv_from(&mut buffer) {
+            Ok((length, _)) => length,
+            Err(_) => continue,
+        };
+
+        let reply = String::from_utf8_lossy(&buffer[..length]);
+        if let Some(location) = header_value(&reply, "location") {
+            if let Ok(url) = location.parse() {
+                return Ok(url);
+            }
+        }
+    }
+}
+
+fn header_value<'a>(message: &'a str, name: &str) -> Option<&'a str> {
+    message.lines().find_map(|line| {
+        let (header, value) = line.split_once(':')?;
+        if header.trim().eq_ignore_ascii_case(name) {
+            Some(value.trim())
+        } else {
+            None
+        }
+    })
+}
+
+/// Returns the service type and control URL of the first WAN connection service in the device
+/// description.
+fn find_wan_service(description: &str) -> Option<(String, String)> {
+    let service_matcher: Regex = r"(?s)<service>(.*?)</service>"
+        .parse()
+        .expect("Programmer error: Invalid regexp");
+
+    let wan_service = service_matcher
+        .captures_iter(description)
+        .find_map(|service| {
+            let service = &service[1];
+            let service_type = xml_element(service, "serviceType")?;
+            let control_url = xml_element(service, "controlURL")?;
+
+            if WAN_SERVICE_TYPES.contains(&service_type.as_str()) {
+                Some((service_type, control_url))
+            } else {
+                None
+            }
+        });
+    wan_service
+}
+
+/// Returns the text inside the first element with the name, ignoring any namespace prefix.
+fn xml_element(xml: &str, name: &str) -> Option<String> {
+    let matcher: Regex = format!(r"(?s)<(?:\w+:)?{0}(?:\s[^>]*)?>(.*?)</(?:\w+:)?{0}>", name)
+        .parse()
+        .expect("Programmer error: Invalid regexp");
+
+    matcher
+        .captures(xml)
+        .map(|captures| captures[1].trim().to_string())
+}
@@ -0,0 +1,45 @@
+//! Details about the build, for the `version` subcommand.
+
+use crate::options::{Options, OutputFormat};
+use serde::Serialize;
+
+#[derive(Serialize, Debug)]
+struct VersionInfo {
+    version: &'static str,
+    git_commit: &'static str,
+    rust_version: &'static str,
+    features: Vec<&'static str>,
+}
+
+/// Prints the version of the program along with how it was built on stdout.
+pub fn print_version(options: &Options) -> Result<(), String> {
+    let info = VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_commit: env!("BUILD_GIT_COMMIT"),
+        rust_version: env!("BUILD_RUST_VERSION"),
+        features: env!("BUILD_FEATURES")
+            .split(',')
+            .filter(|feature| !feature.is_empty())
+            .collect(),
+    };
+
+    match options.output {
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&info)
+                .map_err(|err| format!("Failed to serialize version: {}", err))?
+        ),
+        OutputFormat::Text => {
+            println!("Version:    {}", info.version);
+            println!("Git commit: {}", info.git_commit);
+            println!("Rust:       {}", info.rust_version);
+            if info.features.is_empty() {
+                println!("Features:   none");
+            } else {
+                println!("Features:   {}", info.features.join(", "));
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,88 @@
+use cloudflare_dyndns::early_verify_winner;
+use std::collections::HashMap;
+
+const TWO_THIRDS: f64 = 2.0 / 3.0;
+
+fn votes(tallies: &[(&str, u16)]) -> HashMap<String, u16> {
+    tallies
+        .iter()
+        .map(|(ip, tally)| (ip.to_string(), *tally))
+        .collect()
+}
+
+#[test]
+fn no_votes_has_no_winner() {
+    assert_eq!(early_verify_winner(&votes(&[]), 7, Some(TWO_THIRDS)), None);
+}
+
+#[test]
+fn waits_while_pending_replies_could_reach_the_threshold() {
+    // 3 of 7 agree. If the other 4 agree on another IP, that IP wins instead.
+    let votes = votes(&[("192.0.2.1", 3)]);
+    assert_eq!(early_verify_winner(&votes, 4, Some(TWO_THIRDS)), None);
+}
+
+#[test]
+fn decides_when_the_threshold_holds_even_if_every_pending_reply_disagrees() {
+    // 5 of 7 agree. Even if the last 2 disagree, 5 of 7 is more than 2/3.
+    let votes = votes(&[("192.0.2.1", 5)]);
+    assert_eq!(
+        early_verify_winner(&votes, 2, Some(TWO_THIRDS)),
+        Some("192.0.2.1")
+    );
+}
+
+#[test]
+fn counts_existing_disagreement_against_the_leader() {
+    // 5 agree and 1 disagrees with 1 pending: 5 of 7 is still enough.
+    let enough = votes(&[("192.0.2.1", 5), ("198.51.100.1", 1)]);
+    assert_eq!(
+        early_verify_winner(&enough, 1, Some(TWO_THIRDS)),
+        Some("192.0.2.1")
+    );
+
+    // 4 agree and 2 disagree with 1 pending: 4 of 7 is not enough.
+    let not_enough = votes(&[("192.0.2.1", 4), ("198.51.100.1", 2)]);
+    assert_eq!(early_verify_winner(&not_enough, 1, Some(TWO_THIRDS)), None);
+}
+
+#[test]
+fn never_declares_a_winner_that_could_be_tied() {
+    // With a low threshold both IPs could pass it, so the pending reply decides who leads.
+    let votes = votes(&[("192.0.2.1", 2), ("198.51.100.1", 1)]);
+    assert_eq!(early_verify_winner(&votes, 1, Some(0.25)), None);
+    assert_eq!(
+        early_verify_winner(&votes, 0, Some(0.25)),
+        Some("192.0.2.1")
+    );
+}
+
+#[test]
+fn requires_everything_with_a_threshold_of_one() {
+    let votes = votes(&[("192.0.2.1", 6)]);
+    assert_eq!(early_verify_winner(&votes, 1, Some(1.0)), None);
+    assert_eq!(early_verify_winner(&votes, 0, Some(1.0)), Some("192.0.2.1"));
+}
+
+#[test]
+fn rounds_the_default_threshold_down_to_whole_votes() {
+    // Without --verify-threshold, 2/3 of 4 and of 7 votes round down to 2 and 4 votes.
+    let two_of_four = votes(&[("192.0.2.1", 2), ("198.51.100.1", 1), ("198.51.100.2", 1)]);
+    assert_eq!(
+        early_verify_winner(&two_of_four, 0, None),
+        Some("192.0.2.1")
+    );
+
+    let four_of_seven = votes(&[("192.0.2.1", 4), ("198.51.100.1", 2)]);
+    assert_eq!(
+        early_verify_winner(&four_of_seven, 1, None),
+        Some("192.0.2.1")
+    );
+    assert_eq!(
+        early_verify_winner(&four_of_seven, 1, Some(TWO_THIRDS)),
+        None
+    );
+
+    let one_of_three = votes(&[("192.0.2.1", 1), ("198.51.100.1", 1), ("198.51.100.2", 1)]);
+    assert_eq!(early_verify_winner(&one_of_three, 0, None), None);
+}
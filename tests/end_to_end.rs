@@ -0,0 +1,1131 @@
+//! Runs the binary against a local mock of the Cloudflare API and of an IP service.
+
+use clap::Parser;
+use cloudflare_dyndns::{determine_external_ip, Options};
+use reqwest::Url;
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Command, Output};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const CURRENT_IP: &str = "203.0.113.7";
+const OUTDATED_IP: &str = "198.51.100.1";
+/// A timestamp in 2100, as seen after the clock has been set back.
+const FUTURE_TIMESTAMP: u64 = 4_102_444_800;
+
+#[derive(Clone, Debug)]
+struct Request {
+    method: String,
+    path: String,
+    body: String,
+}
+
+type Handler = dyn Fn(&Request) -> (u16, String) + Send + Sync;
+
+/// A tiny HTTP server that answers every request with the handler, and remembers the requests.
+struct MockServer {
+    url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl MockServer {
+    fn start(handler: impl Fn(&Request) -> (u16, String) + Send + Sync + 'static) -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Could not start mock server");
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let handler: Arc<Handler> = Arc::new(handler);
+
+        let server_requests = Arc::clone(&requests);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let request = match read_request(&stream) {
+                    Some(request) => request,
+                    None => continue,
+                };
+                let (status, body) = handler(&request);
+                server_requests.lock().unwrap().push(request);
+                write_response(stream, status, &body);
+            }
+        });
+
+        MockServer { url, requests }
+    }
+
+    fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    fn requests_with_method(&self, method: &str) -> Vec<Request> {
+        self.requests()
+            .into_iter()
+            .filter(|request| request.method == method)
+            .collect()
+    }
+}
+
+fn read_request(stream: &TcpStream) -> Option<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).ok()?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).ok()?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().ok()?;
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).ok()?;
+
+    Some(Request {
+        method,
+        path,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    })
+}
+
+fn write_response(mut stream: TcpStream, status: u16, body: &str) {
+    let response = format!(
+        "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes());
+}
+
+/// Answers like the Cloudflare API with a single zone, example.com, that has an A record for
+/// home.example.com pointing to the IP. /ip answers like an IP service and /broken always fails.
+fn cloudflare(record_ip: &'static str) -> impl Fn(&Request) -> (u16, String) {
+    move |request| {
+        let (path, query) = request
+            .path
+            .split_once('?')
+            .unwrap_or((request.path.as_str(), ""));
+
+        match (request.method.as_str(), path) {
+            ("GET", "/ip") => (200, format!("{}\n", CURRENT_IP)),
+            ("GET", "/loopback") => (200, String::from("127.0.0.1\n")),
+            ("GET", "/broken") => (500, String::from("Internal Server Error")),
+            ("GET", "/client/v4/zones") if query.contains("name=example.com") => {
+                success(json!([zone()]), Some(single_page(1)))
+            }
+            ("GET", "/client/v4/zones") if query.contains("name=") => {
+                success(json!([]), Some(single_page(0)))
+            }
+            // Listing every zone, for deriving the zone from the record name.
+            ("GET", "/client/v4/zones") => success(
+                json!([
+                    zone_named("zone2", "ample.com"),
+                    zone(),
+                    zone_named("zone3", "example.org"),
+                ]),
+                Some(single_page(3)),
+            ),
+            ("GET", "/client/v4/zones/zone1/dns_records") => success(
+                json!([dns_record(json!({ "content": record_ip }))]),
+                Some(single_page(1)),
+            ),
+            ("PUT", "/client/v4/zones/zone1/dns_records/rec1") => {
+                let changes: Value =
+                    serde_json::from_str(&request.body).expect("Invalid request body");
+                success(dns_record(changes), None)
+            }
+            _ => (
+                404,
+                json!({
+                    "success": false,
+                    "errors": [{ "code": 7003, "message": "No route for that URI" }],
+                    "messages": [],
+                    "result": null,
+                })
+                .to_string(),
+            ),
+        }
+    }
+}
+
+fn success(result: Value, result_info: Option<Value>) -> (u16, String) {
+    let body = json!({
+        "success": true,
+        "errors": [],
+        "messages": [],
+        "result": result,
+        "result_info": result_info,
+    });
+    (200, body.to_string())
+}
+
+fn single_page(count: usize) -> Value {
+    json!({
+        "page": 1,
+        "per_page": 100,
+        "total_pages": 1,
+        "count": count,
+        "total_count": count,
+    })
+}
+
+fn zone() -> Value {
+    zone_named("zone1", "example.com")
+}
+
+fn zone_named(id: &str, name: &str) -> Value {
+    json!({
+        "id": id,
+        "name": name,
+        "account": { "id": "account1", "name": "Account" },
+        "created_on": "2020-01-01T00:00:00Z",
+        "development_mode": 0,
+        "meta": {
+            "custom_certificate_quota": 0,
+            "page_rule_quota": 3,
+            "phishing_detected": false,
+            "multiple_railguns_allowed": false,
+        },
+        "modified_on": "2020-01-01T00:00:00Z",
+        "name_servers": [],
+        "owner": { "type": "user", "id": "user1", "email": "user@example.com" },
+        "paused": false,
+        "permissions": [],
+        "status": "active",
+        "type": "full",
+    })
+}
+
+fn dns_record(changes: Value) -> Value {
+    let mut record = json!({
+        "id": "rec1",
+        "name": "home.example.com",
+        "type": "A",
+        "content": OUTDATED_IP,
+        "ttl": 300,
+        "proxied": false,
+        "proxiable": true,
+        "locked": false,
+        "meta": { "auto_added": false },
+        "zone_id": "zone1",
+        "zone_name": "example.com",
+        "created_on": "2020-01-01T00:00:00Z",
+        "modified_on": "2020-01-01T00:00:00Z",
+    });
+    for (key, value) in changes.as_object().expect("Changes must be an object") {
+        record[key] = value.clone();
+    }
+    record
+}
+
+/// Runs the binary against the server for home.example.com, from an empty directory so that no
+/// .env file is loaded.
+fn run(server: &MockServer, ip_services: &[&str], extra_args: &[&str]) -> Output {
+    let mut args = extra_args.to_vec();
+    args.push("home.example.com");
+    if extra_args.contains(&"--zone-name") {
+        run_with_zone(server, ip_services, &[], &args)
+    } else {
+        run_with_zone(server, ip_services, &["--zone-name", "example.com"], &args)
+    }
+}
+
+/// Like `run`, but the zone and the record are up to the caller.
+fn run_with_zone(
+    server: &MockServer,
+    ip_services: &[&str],
+    zone_args: &[&str],
+    extra_args: &[&str],
+) -> Output {
+    let directory = test_directory(server);
+    let services: Vec<String> = ip_services
+        .iter()
+        .map(|path| format!("{}{}", server.url, path))
+        .collect();
+    let services_file = directory.join("ip-services.txt");
+    fs::write(&services_file, services.join("\n")).expect("Could not write IP services file");
+
+    Command::new(env!("CARGO_BIN_EXE_cloudflare-dyndns-rs"))
+        .args(zone_args)
+        .current_dir(&directory)
+        .env_clear()
+        .args(["--token", "token"])
+        .arg("--cloudflare-api-url")
+        .arg(format!("{}/client/v4/", server.url))
+        .arg("--ip-services-file")
+        .arg(&services_file)
+        .arg("--no-preflight")
+        .args(extra_args)
+        .output()
+        .expect("Could not run binary")
+}
+
+fn test_directory(server: &MockServer) -> PathBuf {
+    let port = server.url.rsplit(':').next().unwrap();
+    let directory = std::env::temp_dir().join(format!("cloudflare-dyndns-test-{}", port));
+    fs::create_dir_all(&directory).expect("Could not create test directory");
+    directory
+}
+
+/// Writes a state file for the server's test, and returns the arguments to use it.
+fn state_file(server: &MockServer, state: Value) -> Vec<String> {
+    let path = test_directory(server).join("state.json");
+    fs::write(&path, state.to_string()).expect("Could not write state file");
+    vec![
+        String::from("--state-file"),
+        path.to_string_lossy().into_owned(),
+    ]
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn updates_an_outdated_record() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+
+    let output = run(&server, &["/ip"], &[]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), format!("{}\n", CURRENT_IP));
+    assert!(server
+        .requests()
+        .iter()
+        .any(|request| request.path == "/client/v4/zones?name=example.com"));
+
+    let updates = server.requests_with_method("PUT");
+    assert_eq!(updates.len(), 1);
+    assert_eq!(updates[0].path, "/client/v4/zones/zone1/dns_records/rec1");
+    let body: Value = serde_json::from_str(&updates[0].body).unwrap();
+    assert_eq!(body["content"], CURRENT_IP);
+    assert_eq!(body["name"], "home.example.com");
+}
+
+#[test]
+fn leaves_a_correct_record_alone() {
+    let server = MockServer::start(cloudflare(CURRENT_IP));
+
+    let output = run(&server, &["/ip"], &[]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), format!("{}\n", CURRENT_IP));
+    assert!(stderr(&output).contains("already correct"));
+    assert!(server.requests_with_method("PUT").is_empty());
+}
+
+#[test]
+fn dry_run_does_not_write() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+
+    let output = run(&server, &["/ip"], &["--dry-run"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Would update DNS record"));
+    assert!(server.requests_with_method("PUT").is_empty());
+}
+
+#[test]
+fn skips_failing_ip_services() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+
+    let output = run(&server, &["/broken", "/ip"], &[]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(server.requests_with_method("PUT").len(), 1);
+}
+
+#[test]
+fn reports_when_no_ip_service_answers() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+
+    let output = run(&server, &["/broken"], &[]);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Error: "));
+    assert!(stdout(&output).is_empty());
+    assert!(server.requests_with_method("PUT").is_empty());
+}
+
+#[test]
+fn reports_a_missing_zone() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+
+    let output = run(&server, &["/ip"], &["--zone-name", "example.org"]);
+
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = stderr(&output);
+    assert!(
+        stderr.starts_with("Error: Failed to retrieve zone ID"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("example.org"), "{}", stderr);
+}
+
+#[test]
+fn derives_the_zone_from_the_record_name() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+
+    let output = run_with_zone(&server, &["/ip"], &[], &["home.example.com"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let updates = server.requests_with_method("PUT");
+    assert_eq!(updates.len(), 1);
+    assert_eq!(updates[0].path, "/client/v4/zones/zone1/dns_records/rec1");
+}
+
+#[test]
+fn determines_the_external_ip_from_an_ip_service() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+    let options = Options::try_parse_from([
+        "cloudflare-dyndns-rs",
+        "--token",
+        "token",
+        "--zone-id",
+        "zone1",
+        "home.example.com",
+    ])
+    .expect("Invalid test options");
+    let services: Vec<Url> = ["/broken", "/ip"]
+        .iter()
+        .map(|path| format!("{}{}", server.url, path).parse().unwrap())
+        .collect();
+
+    let ip = determine_external_ip(&options, &services);
+
+    assert_eq!(ip, Ok(CURRENT_IP.parse::<Ipv4Addr>().unwrap()));
+    assert_eq!(server.requests().len(), 2);
+}
+
+#[test]
+fn quiet_only_prints_the_result() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+
+    let output = run(&server, &["/ip"], &["--quiet"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), format!("{}\n", CURRENT_IP));
+    assert_eq!(stderr(&output), "");
+    assert_eq!(server.requests_with_method("PUT").len(), 1);
+}
+
+#[test]
+fn update_at_most_every_skips_recent_updates() {
+    let server = MockServer::start(cloudflare(CURRENT_IP));
+    let mut args = state_file(&server, json!({ "last_update": now() }));
+    args.extend(["--ttl", "120", "--update-at-most-every", "1h"].map(String::from));
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let output = run(&server, &["/ip"], &args);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(server.requests_with_method("PUT").is_empty());
+}
+
+#[test]
+fn update_at_most_every_counts_as_passed_after_the_clock_was_set_back() {
+    let server = MockServer::start(cloudflare(CURRENT_IP));
+    let mut args = state_file(&server, json!({ "last_update": FUTURE_TIMESTAMP }));
+    args.extend(["--ttl", "120", "--update-at-most-every", "1h"].map(String::from));
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let output = run(&server, &["/ip"], &args);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(server.requests_with_method("PUT").len(), 1);
+}
+
+#[test]
+fn uses_a_recent_cached_ip_when_detection_fails() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+    let mut args = state_file(
+        &server,
+        json!({ "last_ip": "192.0.2.1", "last_ip_detected": now() }),
+    );
+    args.push(String::from("--use-cached-on-failure"));
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let output = run(&server, &["/broken"], &args);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "192.0.2.1\n");
+}
+
+#[test]
+fn does_not_use_a_cached_ip_from_after_the_clock_was_set_back() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+    let mut args = state_file(
+        &server,
+        json!({ "last_ip": "192.0.2.1", "last_ip_detected": FUTURE_TIMESTAMP }),
+    );
+    args.push(String::from("--use-cached-on-failure"));
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let output = run(&server, &["/broken"], &args);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("clock"), "{}", stderr(&output));
+    assert!(server.requests_with_method("PUT").is_empty());
+}
+
+#[test]
+fn exit_code_on_change_reports_drift_in_dry_runs() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+
+    let output = run(&server, &["/ip"], &["--dry-run", "--exit-code-on-change"]);
+
+    assert_eq!(output.status.code(), Some(5), "{}", stderr(&output));
+    assert_eq!(stdout(&output), format!("{}\n", CURRENT_IP));
+    assert!(stderr(&output).contains("Would update DNS record"));
+    assert!(stderr(&output).contains("\nOutdated: The record would be changed without --dry-run\n"));
+    assert!(!stderr(&output).contains("Error:"));
+    assert!(server.requests_with_method("PUT").is_empty());
+}
+
+#[test]
+fn exit_code_on_change_succeeds_without_drift() {
+    let server = MockServer::start(cloudflare(CURRENT_IP));
+
+    let output = run(&server, &["/ip"], &["--dry-run", "--exit-code-on-change"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+}
+
+#[test]
+fn pre_update_command_can_veto_the_update() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+
+    let output = run(
+        &server,
+        &["/ip"],
+        &["--pre-update-command", "echo VPN is up >&2; exit 1"],
+    );
+
+    assert_eq!(output.status.code(), Some(7), "{}", stderr(&output));
+    let stderr = stderr(&output);
+    assert!(stderr.contains("\nVetoed: "), "{}", stderr);
+    assert!(stderr.contains("VPN is up"), "{}", stderr);
+    assert!(server.requests_with_method("PUT").is_empty());
+}
+
+#[test]
+fn pre_update_command_gets_the_update_details() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+    let command = format!(
+        "test \"$DYNDNS_RECORD $DYNDNS_OLD_IP $DYNDNS_NEW_IP\" = \"home.example.com {} {}\"",
+        OUTDATED_IP, CURRENT_IP
+    );
+
+    let output = run(&server, &["/ip"], &["--pre-update-command", &command]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(server.requests_with_method("PUT").len(), 1);
+}
+
+#[test]
+fn confirm_count_waits_for_the_ip_to_be_seen_in_enough_runs() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+    let mut args = state_file(&server, json!({ "last_ip": CURRENT_IP, "ip_streak": 1 }));
+    args.extend(["--confirm-count", "3"].map(String::from));
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let output = run(&server, &["/ip"], &args);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(server.requests_with_method("PUT").is_empty());
+
+    // The state file now has the IP seen twice in a row, so the next run confirms it.
+    let output = run(&server, &["/ip"], &args);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(server.requests_with_method("PUT").len(), 1);
+}
+
+#[test]
+fn confirm_count_starts_over_for_a_different_ip() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+    let mut args = state_file(&server, json!({ "last_ip": "192.0.2.1", "ip_streak": 5 }));
+    args.extend(["--confirm-count", "2"].map(String::from));
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let output = run(&server, &["/ip"], &args);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(server.requests_with_method("PUT").is_empty());
+}
+
+#[test]
+fn proxy_on_unhealthy_proxies_a_record_that_fails_the_health_check() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+
+    // The health check gets the 500 from /broken, since the URL keeps pointing at the server.
+    let health_check = format!("http:{}/broken", server.url);
+    let output = run(
+        &server,
+        &["/loopback"],
+        &["--health-check", &health_check, "--proxy-on-unhealthy"],
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("Turning on the Cloudflare proxy"),
+        "{}",
+        stderr(&output)
+    );
+    let updates = server.requests_with_method("PUT");
+    assert_eq!(updates.len(), 1);
+    let body: Value = serde_json::from_str(&updates[0].body).unwrap();
+    assert_eq!(body["content"], "127.0.0.1");
+    assert_eq!(body["proxied"], true);
+}
+
+#[test]
+fn pushes_metrics_to_the_pushgateway() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+
+    let output = run(
+        &server,
+        &["/ip"],
+        &[
+            "--pushgateway-url",
+            &server.url,
+            "--pushgateway-instance",
+            "host1",
+        ],
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let pushes: Vec<Request> = server
+        .requests_with_method("PUT")
+        .into_iter()
+        .filter(|request| request.path.starts_with("/metrics/"))
+        .collect();
+    assert_eq!(pushes.len(), 1);
+    assert_eq!(
+        pushes[0].path,
+        "/metrics/job/cloudflare_dyndns/instance/host1/record/home.example.com"
+    );
+    assert!(pushes[0]
+        .body
+        .contains("\ncloudflare_dyndns_last_run_success 1\n"));
+    assert!(pushes[0]
+        .body
+        .contains("\ncloudflare_dyndns_last_run_updated 1\n"));
+}
+
+#[test]
+fn rejects_a_pushgateway_url_that_is_not_http() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+
+    let output = run(
+        &server,
+        &["/ip"],
+        &["--pushgateway-url", "mailto:ops@example.com"],
+    );
+
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Unsupported scheme mailto"));
+    assert!(server.requests().is_empty());
+}
+
+#[test]
+fn retries_a_failed_zone_lookup() {
+    let failed_once = AtomicBool::new(false);
+    let cloudflare = cloudflare(OUTDATED_IP);
+    let server = MockServer::start(move |request| {
+        if request.path.starts_with("/client/v4/zones?") && !failed_once.swap(true, SeqCst) {
+            return (503, String::from("Service Unavailable"));
+        }
+        cloudflare(request)
+    });
+
+    let output = run(&server, &["/ip"], &[]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let lookups = server
+        .requests()
+        .into_iter()
+        .filter(|request| request.path.starts_with("/client/v4/zones?"))
+        .count();
+    assert_eq!(lookups, 2);
+    assert_eq!(server.requests_with_method("PUT").len(), 1);
+}
+
+#[test]
+fn max_concurrent_ip_requests_limits_how_many_services_verify_asks() {
+    let server = MockServer::start(cloudflare(CURRENT_IP));
+
+    let output = run(
+        &server,
+        &["/ip?1", "/ip?2", "/ip?3"],
+        &["--verify", "--max-concurrent-ip-requests", "1", "-vv"],
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Asking up to 1 service(s) at a time"));
+    // After two agreeing answers the vote is decided, so the third service is never asked.
+    let ip_requests = server
+        .requests()
+        .into_iter()
+        .filter(|request| request.path.starts_with("/ip?"))
+        .count();
+    assert_eq!(ip_requests, 2);
+}
+
+#[test]
+fn patch_overrides_the_written_fields() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+    let patch = test_directory(&server).join("patch.json");
+    fs::write(
+        &patch,
+        r#"{ "content": "192.0.2.10", "ttl": 120, "proxied": true }"#,
+    )
+    .unwrap();
+
+    let output = run(
+        &server,
+        &["/ip"],
+        &["--ttl", "1h", "--patch", patch.to_str().unwrap()],
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let updates = server.requests_with_method("PUT");
+    assert_eq!(updates.len(), 1);
+    let body: Value = serde_json::from_str(&updates[0].body).unwrap();
+    assert_eq!(body["content"], "192.0.2.10");
+    assert_eq!(body["ttl"], 120);
+    assert_eq!(body["proxied"], true);
+    // The IP comes from the patch, so the IP services are not asked.
+    assert!(!server
+        .requests()
+        .iter()
+        .any(|request| request.path == "/ip"));
+}
+
+#[test]
+fn patch_rejects_unknown_fields() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+    let patch = test_directory(&server).join("patch.json");
+    fs::write(&patch, r#"{ "comment": "home" }"#).unwrap();
+
+    let output = run(&server, &["/ip"], &["--patch", patch.to_str().unwrap()]);
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("unknown field `comment`"));
+    assert!(server.requests().is_empty());
+}
+
+#[test]
+fn resolve_connects_to_hosts_at_the_given_ip() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+    let port = server.url.rsplit(':').next().unwrap();
+    let services = test_directory(&server).join("resolved-services.txt");
+    fs::write(&services, format!("http://ip.example.test:{}/ip", port)).unwrap();
+    let api_url = format!("http://api.example.test:{}/client/v4/", port);
+
+    let output = run(
+        &server,
+        &[],
+        &[
+            "--ip-services-file",
+            services.to_str().unwrap(),
+            "--cloudflare-api-url",
+            &api_url,
+            "--resolve",
+            "ip.example.test:127.0.0.1",
+            "--resolve",
+            "api.example.test:127.0.0.1",
+        ],
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(server.requests_with_method("PUT").len(), 1);
+}
+
+#[test]
+fn compare_ip_only_ignores_other_differences() {
+    let server = MockServer::start(cloudflare(CURRENT_IP));
+    let patch = test_directory(&server).join("patch.json");
+    fs::write(&patch, r#"{ "ttl": 120, "proxied": true }"#).unwrap();
+
+    let output = run(
+        &server,
+        &["/ip"],
+        &["--compare-ip-only", "--patch", patch.to_str().unwrap()],
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(server.requests_with_method("PUT").is_empty());
+}
+
+#[test]
+fn compare_ip_only_only_writes_the_ip() {
+    let cloudflare = cloudflare(OUTDATED_IP);
+    let server = MockServer::start(move |request| {
+        if request.method == "GET"
+            && request
+                .path
+                .starts_with("/client/v4/zones/zone1/dns_records")
+        {
+            let record = dns_record(json!({ "proxied": true }));
+            return success(json!([record]), Some(single_page(1)));
+        }
+        cloudflare(request)
+    });
+    let patch = test_directory(&server).join("patch.json");
+    fs::write(&patch, r#"{ "ttl": 120, "proxied": true }"#).unwrap();
+
+    let output = run(
+        &server,
+        &["/ip"],
+        &["--compare-ip-only", "--patch", patch.to_str().unwrap()],
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let updates = server.requests_with_method("PUT");
+    assert_eq!(updates.len(), 1);
+    let body: Value = serde_json::from_str(&updates[0].body).unwrap();
+    assert_eq!(body["content"], CURRENT_IP);
+    assert_eq!(body["ttl"], 300, "{}", body);
+    assert_eq!(body["proxied"], true, "{}", body);
+}
+
+#[test]
+fn finds_a_record_with_an_internationalized_name() {
+    let cloudflare = cloudflare(OUTDATED_IP);
+    let server = MockServer::start(move |request| {
+        if request.method == "GET"
+            && request
+                .path
+                .starts_with("/client/v4/zones/zone1/dns_records")
+        {
+            let record = dns_record(json!({ "name": "xn--bcher-kva.example.com" }));
+            return success(json!([record]), Some(single_page(1)));
+        }
+        cloudflare(request)
+    });
+
+    let output = run_with_zone(
+        &server,
+        &["/ip"],
+        &["--zone-name", "example.com"],
+        &["bücher.example.com"],
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(server
+        .requests()
+        .iter()
+        .any(|request| request.path.contains("name=xn--bcher-kva.example.com")));
+    let updates = server.requests_with_method("PUT");
+    assert_eq!(updates.len(), 1);
+    let body: Value = serde_json::from_str(&updates[0].body).unwrap();
+    assert_eq!(body["name"], "xn--bcher-kva.example.com");
+    assert_eq!(body["content"], CURRENT_IP);
+}
+
+#[test]
+fn matches_an_internationalized_record_suffix() {
+    let cloudflare = cloudflare(OUTDATED_IP);
+    let server = MockServer::start(move |request| {
+        if request.method == "GET"
+            && request
+                .path
+                .starts_with("/client/v4/zones/zone1/dns_records")
+        {
+            let record = dns_record(json!({ "name": "home.xn--bcher-kva.example.com" }));
+            return success(json!([record]), Some(single_page(1)));
+        }
+        cloudflare(request)
+    });
+
+    let output = run_with_zone(
+        &server,
+        &["/ip"],
+        &["--zone-name", "example.com"],
+        &["--record-suffix", ".bücher.example.com"],
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(server.requests_with_method("PUT").len(), 1);
+}
+
+#[test]
+fn record_suffix_only_matches_whole_labels() {
+    let cloudflare = cloudflare(OUTDATED_IP);
+    let server = MockServer::start(move |request| {
+        if request.method == "GET"
+            && request
+                .path
+                .starts_with("/client/v4/zones/zone1/dns_records")
+        {
+            let records = json!([
+                dns_record(json!({ "id": "rec1", "name": "home.example.com" })),
+                dns_record(json!({ "id": "rec2", "name": "nas.home.example.com" })),
+                dns_record(json!({ "id": "rec3", "name": "myhome.example.com" })),
+            ]);
+            return success(records, Some(single_page(3)));
+        }
+        if request.method == "PUT"
+            && request
+                .path
+                .starts_with("/client/v4/zones/zone1/dns_records/")
+        {
+            let changes: Value = serde_json::from_str(&request.body).unwrap();
+            return success(dns_record(changes), None);
+        }
+        cloudflare(request)
+    });
+
+    let output = run_with_zone(
+        &server,
+        &["/ip"],
+        &["--zone-name", "example.com"],
+        &["--record-suffix", "home.example.com"],
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let mut updated: Vec<String> = server
+        .requests_with_method("PUT")
+        .into_iter()
+        .map(|request| request.path)
+        .collect();
+    updated.sort();
+    assert_eq!(
+        updated,
+        [
+            "/client/v4/zones/zone1/dns_records/rec1",
+            "/client/v4/zones/zone1/dns_records/rec2",
+        ]
+    );
+}
+
+#[test]
+fn reports_a_failure_below_the_healthcheck_url() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+    let healthcheck_url = format!("{}/ping/secret-uuid/?rid=1", server.url);
+
+    let output = run(
+        &server,
+        &["/broken"],
+        &["--healthcheck-url", &healthcheck_url],
+    );
+
+    assert!(!output.status.success());
+    let reports = server.requests_with_method("POST");
+    assert_eq!(reports.len(), 1);
+    assert_eq!(reports[0].path, "/ping/secret-uuid/fail?rid=1");
+    assert!(reports[0].body.starts_with("Exit code 1: "));
+    // The ping is not found, and the warning about it does not leak the UUID.
+    assert!(stderr(&output).contains("Failed to report to the healthcheck URL"));
+    assert!(!stderr(&output).contains("secret-uuid"));
+}
+
+#[test]
+fn does_not_log_the_heartbeat_token() {
+    let cloudflare = cloudflare(OUTDATED_IP);
+    let server = MockServer::start(move |request| {
+        if request.path == "/heartbeat/secret-token" {
+            return (200, String::from("OK"));
+        }
+        cloudflare(request)
+    });
+    let heartbeat_url = format!("{}/heartbeat/secret-token", server.url);
+
+    let output = run(
+        &server,
+        &["/ip"],
+        &["--verbose", "--heartbeat-url", &heartbeat_url],
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(server
+        .requests_with_method("GET")
+        .iter()
+        .any(|request| request.path == "/heartbeat/secret-token"));
+    assert!(stderr(&output).contains("Sent heartbeat to "));
+    assert!(!stderr(&output).contains("secret-token"));
+}
+
+#[test]
+fn only_shows_the_body_of_a_failed_reply_at_trace_level() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+
+    let verbose = run(&server, &["/broken", "/ip"], &["-v"]);
+    let debug = run(&server, &["/broken", "/ip"], &["-vv"]);
+    let trace = run(&server, &["/broken", "/ip"], &["-vvv"]);
+
+    assert!(verbose.status.success(), "{}", stderr(&verbose));
+    assert!(stderr(&verbose).contains("Failed. HTTP 500\n"));
+    for output in [&verbose, &debug] {
+        assert!(!stderr(output).contains("Internal Server Error"));
+    }
+    assert_eq!(
+        stderr(&trace).matches("Internal Server Error").count(),
+        1,
+        "{}",
+        stderr(&trace)
+    );
+    assert!(stderr(&trace).contains("  Response body: Internal Server Error\n"));
+    // Bodies of replies that succeeded are not shown.
+    assert!(!stderr(&trace).contains(&format!(": {}\n", CURRENT_IP)));
+}
+
+#[test]
+fn diff_only_prints_only_the_ip_on_stdout() {
+    let server = MockServer::start(cloudflare(CURRENT_IP));
+
+    let output = run(&server, &["/ip"], &["--diff-only", "-v"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), format!("{}\n", CURRENT_IP));
+    assert!(stderr(&output).contains("OK: home.example.com points to 203.0.113.7\n"));
+    assert!(server.requests_with_method("PUT").is_empty());
+}
+
+#[test]
+fn keeps_the_current_ttl_without_ttl() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+
+    let output = run(&server, &["/ip"], &[]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let updates = server.requests_with_method("PUT");
+    assert_eq!(updates.len(), 1);
+    let body: Value = serde_json::from_str(&updates[0].body).unwrap();
+    assert_eq!(body["ttl"], 300, "{}", body);
+}
+
+#[test]
+fn proxied_does_not_take_the_record_as_its_value() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+
+    let output = run(&server, &["/ip"], &["--proxied"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let updates = server.requests_with_method("PUT");
+    assert_eq!(updates.len(), 1);
+    let body: Value = serde_json::from_str(&updates[0].body).unwrap();
+    assert_eq!(body["name"], "home.example.com");
+    assert_eq!(body["proxied"], true);
+}
+
+#[test]
+fn keeps_the_current_proxied_setting_without_proxied() {
+    let cloudflare = cloudflare(OUTDATED_IP);
+    let server = MockServer::start(move |request| {
+        if request.method == "GET"
+            && request
+                .path
+                .starts_with("/client/v4/zones/zone1/dns_records")
+        {
+            let record = dns_record(json!({ "proxied": true }));
+            return success(json!([record]), Some(single_page(1)));
+        }
+        cloudflare(request)
+    });
+
+    let output = run(&server, &["/ip"], &[]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let updates = server.requests_with_method("PUT");
+    assert_eq!(updates.len(), 1);
+    let body: Value = serde_json::from_str(&updates[0].body).unwrap();
+    assert_eq!(body["proxied"], true, "{}", body);
+}
+
+#[test]
+fn dry_runs_leave_the_state_file_alone() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+    let mut args = state_file(&server, json!({ "last_ip": CURRENT_IP, "ip_streak": 1 }));
+    let path = PathBuf::from(&args[1]);
+    let state = fs::read_to_string(&path).unwrap();
+    args.extend(["--confirm-count", "2", "--dry-run"].map(String::from));
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let output = run(&server, &["/ip"], &args);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let output = run(&server, &["/ip"], &["--diff-only", args[0], args[1]]);
+    assert_eq!(output.status.code(), Some(5), "{}", stderr(&output));
+
+    assert_eq!(fs::read_to_string(&path).unwrap(), state);
+}
+
+#[test]
+fn rejects_a_heartbeat_url_that_is_not_http() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+
+    let output = run(
+        &server,
+        &["/ip"],
+        &["--heartbeat-url", "file:///tmp/heartbeat"],
+    );
+
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Unsupported scheme file"));
+    assert!(server.requests().is_empty());
+}
+
+#[test]
+fn rejects_a_notify_url_that_is_not_http() {
+    let server = MockServer::start(cloudflare(OUTDATED_IP));
+
+    let output = run(
+        &server,
+        &["/ip"],
+        &["--notify-url", "ftp://example.com/hook"],
+    );
+
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Unsupported scheme ftp"));
+    assert!(server.requests().is_empty());
+}
+
+#[test]
+fn does_not_log_the_notify_url() {
+    let cloudflare = cloudflare(OUTDATED_IP);
+    let server = MockServer::start(move |request| {
+        if request.path == "/hooks/secret-token" {
+            return (200, String::from("OK"));
+        }
+        cloudflare(request)
+    });
+    let notify_url = format!("{}/hooks/secret-token", server.url);
+
+    let output = run(
+        &server,
+        &["/ip"],
+        &["--verbose", "--notify-url", &notify_url],
+    );
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(server.requests_with_method("POST").len(), 1);
+    assert!(stderr(&output).contains("Sent notification to "));
+    assert!(!stderr(&output).contains("secret-token"));
+}
@@ -0,0 +1,136 @@
+use clap::Parser;
+use cloudflare_dyndns::{determine_external_ip_from_sources, IpSource, Options, SourceError};
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// A source that always gives the same answer, and counts how often it was asked.
+struct MockSource {
+    name: &'static str,
+    ip: Option<Ipv4Addr>,
+    calls: AtomicUsize,
+}
+
+impl MockSource {
+    fn new(name: &'static str, ip: Option<[u8; 4]>) -> Arc<MockSource> {
+        Arc::new(MockSource {
+            name,
+            ip: ip.map(Ipv4Addr::from),
+            calls: AtomicUsize::new(0),
+        })
+    }
+
+    fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+}
+
+impl IpSource for MockSource {
+    fn name(&self) -> String {
+        self.name.to_string()
+    }
+
+    fn fetch_ip(&self) -> Result<Ipv4Addr, SourceError> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        self.ip
+            .ok_or_else(|| SourceError::Failed(String::from("Mock failure")))
+    }
+}
+
+fn options(extra_args: &[&str]) -> Options {
+    let args = ["cloudflare-dyndns-rs", "--token", "x", "--zone-id", "zone"]
+        .iter()
+        .chain(extra_args)
+        .chain(["home.example.com"].iter());
+    Options::try_parse_from(args).expect("Invalid test options")
+}
+
+fn sources(mocks: &[&Arc<MockSource>]) -> Vec<Arc<dyn IpSource>> {
+    mocks
+        .iter()
+        .map(|mock| Arc::clone(mock) as Arc<dyn IpSource>)
+        .collect()
+}
+
+#[test]
+fn first_strategy_uses_the_first_source_that_answers() {
+    let failing = MockSource::new("failing", None);
+    let first = MockSource::new("first", Some([192, 0, 2, 1]));
+    let unused = MockSource::new("unused", Some([198, 51, 100, 1]));
+
+    let ip =
+        determine_external_ip_from_sources(&options(&[]), &sources(&[&failing, &first, &unused]));
+
+    assert_eq!(ip, Ok(Ipv4Addr::new(192, 0, 2, 1)));
+    assert_eq!(failing.calls(), 1);
+    assert_eq!(unused.calls(), 0);
+}
+
+#[test]
+fn fails_when_no_source_answers() {
+    let sources = sources(&[&MockSource::new("a", None), &MockSource::new("b", None)]);
+
+    assert!(determine_external_ip_from_sources(&options(&[]), &sources).is_err());
+}
+
+#[test]
+fn verify_uses_the_ip_with_enough_votes() {
+    let sources = sources(&[
+        &MockSource::new("a", Some([192, 0, 2, 1])),
+        &MockSource::new("b", Some([192, 0, 2, 1])),
+        &MockSource::new("c", Some([192, 0, 2, 1])),
+        &MockSource::new("d", Some([198, 51, 100, 1])),
+    ]);
+
+    let ip = determine_external_ip_from_sources(&options(&["--verify"]), &sources);
+
+    assert_eq!(ip, Ok(Ipv4Addr::new(192, 0, 2, 1)));
+}
+
+#[test]
+fn verify_fails_without_enough_votes() {
+    let sources = sources(&[
+        &MockSource::new("a", Some([192, 0, 2, 1])),
+        &MockSource::new("b", Some([198, 51, 100, 1])),
+        &MockSource::new("c", Some([203, 0, 113, 1])),
+    ]);
+
+    assert!(determine_external_ip_from_sources(&options(&["--verify"]), &sources).is_err());
+}
+
+#[test]
+fn verify_rounds_the_default_threshold_down() {
+    // 2/3 of 4 votes rounds down to 2 votes.
+    let sources = sources(&[
+        &MockSource::new("a", Some([192, 0, 2, 1])),
+        &MockSource::new("b", Some([198, 51, 100, 1])),
+        &MockSource::new("c", Some([192, 0, 2, 1])),
+        &MockSource::new("d", Some([203, 0, 113, 1])),
+    ]);
+
+    let ip = determine_external_ip_from_sources(&options(&["--verify"]), &sources);
+
+    assert_eq!(ip, Ok(Ipv4Addr::new(192, 0, 2, 1)));
+}
+
+#[test]
+fn consensus_lite_requires_the_quorum_to_agree() {
+    let agreeing = sources(&[
+        &MockSource::new("a", Some([192, 0, 2, 1])),
+        &MockSource::new("b", None),
+        &MockSource::new("c", Some([192, 0, 2, 1])),
+        &MockSource::new("d", Some([192, 0, 2, 1])),
+    ]);
+    let disagreeing = sources(&[
+        &MockSource::new("a", Some([192, 0, 2, 1])),
+        &MockSource::new("b", Some([198, 51, 100, 1])),
+        &MockSource::new("c", Some([192, 0, 2, 1])),
+    ]);
+    let options = options(&["--select-strategy", "consensus-lite"]);
+
+    assert_eq!(
+        determine_external_ip_from_sources(&options, &agreeing),
+        Ok(Ipv4Addr::new(192, 0, 2, 1))
+    );
+    assert!(determine_external_ip_from_sources(&options, &disagreeing).is_err());
+}